@@ -0,0 +1,60 @@
+//! Measures the cost of the uptime header field per record.
+//!
+//! Run with `cargo bench --bench uptime`. Records are written to a sink that
+//! discards them, so the numbers are dominated by filtering and formatting.
+
+use std::hint::black_box;
+use std::io;
+use std::time::{Duration, Instant};
+use hilog::{Builder, Logger, Sink, SinkRecord};
+use log::{Level, LevelFilter, Log, Record};
+
+const RECORDS: u32 = 200_000;
+
+struct Discard;
+
+impl Sink for Discard {
+    fn write(&self, _record: &SinkRecord<'_>, msg: &[u8]) -> io::Result<()> {
+        black_box(msg);
+        Ok(())
+    }
+}
+
+fn logger(uptime: bool) -> Logger {
+    let mut builder = Builder::new();
+    builder
+        .filter_level(LevelFilter::Info)
+        .format_uptime(uptime)
+        .sink(Discard);
+    builder.build()
+}
+
+fn run(logger: &Logger) -> Duration {
+    let start = Instant::now();
+    for i in 0..RECORDS {
+        logger.log(
+            &Record::builder()
+                .level(Level::Info)
+                .target("bench")
+                .args(format_args!("record {}", i))
+                .build(),
+        );
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let without = logger(false);
+    let with = logger(true);
+
+    // Warm up the thread-local formatter, so its buffer has grown already.
+    run(&without);
+    run(&with);
+
+    let without = run(&without);
+    let with = run(&with);
+    let per_record = |elapsed: Duration| elapsed.as_nanos() / u128::from(RECORDS);
+
+    println!("without uptime: {} ns/record", per_record(without));
+    println!("with uptime:    {} ns/record", per_record(with));
+}
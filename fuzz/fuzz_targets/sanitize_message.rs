@@ -0,0 +1,25 @@
+#![no_main]
+
+use hilog::{sanitize_message, SanitizeOptions};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // The first two bytes select the options, the rest is the message.
+    let [flags, max_len, msg @ ..] = data else {
+        return;
+    };
+
+    let mut opts = SanitizeOptions::default();
+    opts.validate_utf8 = flags & 1 != 0;
+    opts.max_len = (flags & 2 != 0).then_some(usize::from(*max_len));
+
+    let sanitized = sanitize_message(msg, &opts);
+
+    assert!(!sanitized.contains(&0), "NUL byte in sanitized message");
+    if let Some(max_len) = opts.max_len {
+        assert!(sanitized.len() <= max_len, "sanitized message too long");
+    }
+    if opts.validate_utf8 {
+        assert!(std::str::from_utf8(&sanitized).is_ok(), "invalid UTF-8 in sanitized message");
+    }
+});
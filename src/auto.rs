@@ -0,0 +1,66 @@
+use std::env;
+use std::io::{self, Write};
+use log::{Log, Metadata, Record};
+use crate::Builder;
+
+/// The environment variable read by [`auto_logger`].
+const FILTER_ENV: &str = "RUST_LOG";
+
+/// Returns a logger suited to the target the program was built for.
+///
+/// On OpenHarmony this is a hilog [`Logger`](crate::Logger) with the default
+/// settings. On other targets, e.g. when running tests of a library on the
+/// development host, records are written to stderr instead. Both use the same
+/// filter, parsed from the `RUST_LOG` environment variable, so that records are
+/// filtered identically everywhere.
+///
+/// The logger is not installed; pass it to [`log::set_boxed_logger`] and set the
+/// maximum level, or nest it within another logger.
+///
+/// # Examples
+///
+/// ```no_run
+/// let logger = hilog::auto_logger();
+/// log::set_boxed_logger(logger).expect("no logger installed yet");
+/// log::set_max_level(log::LevelFilter::Trace);
+/// ```
+pub fn auto_logger() -> Box<dyn Log> {
+    let filter = env::var(FILTER_ENV).unwrap_or_default();
+
+    if cfg!(target_env = "ohos") {
+        let mut builder = Builder::new();
+        builder.parse_filters(&filter);
+        Box::new(builder.build())
+    } else {
+        let filter = env_filter::Builder::new().parse(&filter).build();
+        Box::new(StderrLogger { filter })
+    }
+}
+
+/// A line based logger to stderr, for targets without hilog.
+struct StderrLogger {
+    filter: env_filter::Filter,
+}
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        let _ = writeln!(
+            io::stderr().lock(),
+            "[{:<5} {}] {}",
+            record.level(),
+            crate::strip_target_markers(record.target()),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
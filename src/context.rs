@@ -0,0 +1,126 @@
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::io;
+use std::marker::PhantomData;
+
+thread_local! {
+    static CONTEXT: RefCell<Vec<(String, String)>> = const { RefCell::new(Vec::new()) };
+    static CORRELATION_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Adds a key-value pair to the logging context of the current thread.
+///
+/// While the returned guard is alive, the default format appends `key=value`
+/// to every record logged on this thread. Guards are expected to be dropped in
+/// reverse order of creation; dropping a guard also removes all pairs pushed
+/// after it.
+///
+/// # Examples
+///
+/// ```
+/// let _guard = hilog::push_context("request_id", 42);
+/// log::info!("handling request"); // handling request request_id=42
+/// ```
+pub fn push_context(key: impl Into<String>, value: impl Display) -> ContextGuard {
+    let entry = (key.into(), value.to_string());
+    let depth = CONTEXT
+        .try_with(|ctx| {
+            let mut ctx = ctx.borrow_mut();
+            ctx.push(entry);
+            ctx.len() - 1
+        })
+        .unwrap_or(0);
+
+    ContextGuard {
+        depth,
+        _not_send: PhantomData,
+    }
+}
+
+/// Removes a key-value pair from the logging context when dropped.
+///
+/// Created by [`push_context`].
+#[must_use = "the context is removed when the guard is dropped"]
+pub struct ContextGuard {
+    depth: usize,
+    // The context is thread-local, so the guard must stay on its thread.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        let _ = CONTEXT.try_with(|ctx| ctx.borrow_mut().truncate(self.depth));
+    }
+}
+
+/// Calls `f` for every key-value pair in the context of the current thread.
+pub(crate) fn for_each<F>(mut f: F) -> io::Result<()>
+where
+    F: FnMut(&str, &str) -> io::Result<()>,
+{
+    CONTEXT
+        .try_with(|ctx| match ctx.try_borrow() {
+            Ok(ctx) => ctx.iter().try_for_each(|(key, value)| f(key, value)),
+            // The context is currently being modified.
+            Err(_) => Ok(()),
+        })
+        .unwrap_or(Ok(()))
+}
+
+/// Whether the context of the current thread contains `key`.
+pub(crate) fn contains(key: &str) -> bool {
+    CONTEXT
+        .try_with(|ctx| match ctx.try_borrow() {
+            Ok(ctx) => ctx.iter().any(|(k, _)| k == key),
+            Err(_) => false,
+        })
+        .unwrap_or(false)
+}
+
+/// Calls `f` for every pair of `defaults` whose key is not in the context of
+/// the current thread, then for every pair of the context.
+pub(crate) fn for_each_with_defaults<F>(defaults: &[(String, String)], mut f: F) -> io::Result<()>
+where
+    F: FnMut(&str, &str) -> io::Result<()>,
+{
+    for (key, value) in defaults {
+        // The context of the thread overrides the defaults.
+        if !contains(key) {
+            f(key, value)?;
+        }
+    }
+    for_each(f)
+}
+
+/// Sets the correlation id of the current thread, or removes it.
+///
+/// With [`Builder::format_correlation_id`](crate::Builder::format_correlation_id),
+/// the default format writes it as a header field, e.g. `cid=4bf92f35`, so log
+/// lines can be joined with traces. Unlike [`push_context`], there is only one
+/// correlation id per thread, which stays set until it is changed.
+///
+/// # Examples
+///
+/// ```
+/// hilog::set_correlation_id(Some("4bf92f35".to_owned()));
+/// log::info!("handling request"); // [INFO  cid=4bf92f35 my_app] handling request
+/// hilog::set_correlation_id(None);
+/// ```
+pub fn set_correlation_id(id: Option<String>) {
+    let _ = CORRELATION_ID.try_with(|cid| {
+        if let Ok(mut cid) = cid.try_borrow_mut() {
+            *cid = id;
+        }
+    });
+}
+
+/// Calls `f` with the correlation id of the current thread, if there is one.
+pub(crate) fn with_correlation_id<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&str) -> R,
+{
+    CORRELATION_ID
+        .try_with(|cid| cid.try_borrow().ok().and_then(|cid| cid.as_deref().map(f)))
+        .ok()
+        .flatten()
+}
@@ -0,0 +1,72 @@
+use std::iter;
+use log::Record;
+use crate::ohfmt::writer::GroupEntry;
+use crate::{HilogFormatter, LogDomain, Logger, ReentrancyGuard};
+
+/// Collects records to be printed as one block.
+///
+/// Created by [`Logger::group`].
+pub struct LogGroup<'a> {
+    logger: &'a Logger,
+    entries: Vec<GroupEntry>,
+}
+
+impl<'a> LogGroup<'a> {
+    pub(crate) fn new(logger: &'a Logger) -> Self {
+        LogGroup {
+            logger,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Filters and formats `record` and adds it to the group.
+    pub fn log(&mut self, record: &Record<'_>) {
+        // Routing and formatting run user code, like `Logger::log`.
+        let Some(_guard) = ReentrancyGuard::enter() else {
+            self.logger.writer.counters().dropped();
+            self.logger.report_reentrant_log();
+            return;
+        };
+
+        let Some(route) = self.logger.route(record) else {
+            return;
+        };
+
+        let mut formatter = HilogFormatter::new(&self.logger.writer);
+        if self.logger.format_record(&mut formatter, record, &route).is_err() {
+            self.logger.writer.counters().dropped();
+            return;
+        }
+
+        // Printed to its own domain first, then to the mirrors, like `Logger::log`.
+        let mirrors: Vec<LogDomain> = self.logger.mirrors(record, &route).collect();
+        let msg = formatter.take();
+        for domain in iter::once(route.domain).chain(mirrors) {
+            self.entries.push(GroupEntry {
+                msg: msg.clone(),
+                log_type: route.log_type,
+                level: route.level,
+                domain,
+                tag: route.tag.clone().into_owned(),
+                private: route.private,
+                mirror: domain != route.domain,
+            });
+        }
+    }
+
+    /// Prints all collected records.
+    pub(crate) fn flush(self) {
+        if self.entries.is_empty() {
+            return;
+        }
+        // The `on_emit` callback and the sink may log, too.
+        let Some(_guard) = ReentrancyGuard::enter() else {
+            for _ in self.entries.iter().filter(|entry| !entry.mirror) {
+                self.logger.writer.counters().dropped();
+            }
+            self.logger.report_reentrant_log();
+            return;
+        };
+        self.logger.writer.print_group(self.entries);
+    }
+}
@@ -0,0 +1,142 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use log::{LevelFilter, Metadata};
+
+/// Temporary per-module level overrides, shared between a `Logger` and its handles.
+pub(crate) struct LevelOverrides {
+    // Fast path, so that loggers without overrides never take the lock.
+    active: AtomicBool,
+    next_id: AtomicU64,
+    state: RwLock<State>,
+}
+
+#[derive(Default)]
+struct State {
+    entries: Vec<Override>,
+    // The global max level from before an override had to raise it.
+    restore_max_level: Option<LevelFilter>,
+}
+
+struct Override {
+    id: u64,
+    module: String,
+    level: LevelFilter,
+}
+
+impl LevelOverrides {
+    pub(crate) fn new() -> Self {
+        LevelOverrides {
+            active: AtomicBool::new(false),
+            next_id: AtomicU64::new(0),
+            state: RwLock::new(State::default()),
+        }
+    }
+
+    /// Returns the overridden level for the target of `metadata`, if any.
+    ///
+    /// If several overrides match, the one for the most specific module wins.
+    pub(crate) fn level_for(&self, metadata: &Metadata<'_>) -> Option<LevelFilter> {
+        if !self.active.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        let target = metadata.target();
+        let state = self.state.read().unwrap_or_else(|e| e.into_inner());
+        state
+            .entries
+            .iter()
+            // Same prefix matching as the `env_filter` directives.
+            .filter(|o| target.starts_with(&*o.module))
+            .max_by_key(|o| o.module.len())
+            .map(|o| o.level)
+    }
+
+    fn push(&self, module: &str, level: LevelFilter) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut state = self.state.write().unwrap_or_else(|e| e.into_inner());
+        state.entries.push(Override { id, module: module.to_owned(), level });
+        self.active.store(true, Ordering::Relaxed);
+
+        let max_level = log::max_level();
+        if level > max_level {
+            state.restore_max_level.get_or_insert(max_level);
+            log::set_max_level(level);
+        }
+        id
+    }
+
+    fn remove(&self, id: u64) {
+        let mut state = self.state.write().unwrap_or_else(|e| e.into_inner());
+        state.entries.retain(|o| o.id != id);
+        self.active.store(!state.entries.is_empty(), Ordering::Relaxed);
+
+        if let Some(restore) = state.restore_max_level {
+            let max_level = state
+                .entries
+                .iter()
+                .map(|o| o.level)
+                .fold(restore, |a, b| a.max(b));
+            log::set_max_level(max_level);
+
+            if state.entries.is_empty() {
+                state.restore_max_level = None;
+            }
+        }
+    }
+}
+
+/// A handle to change the behaviour of a [`Logger`](crate::Logger) after it was built.
+///
+/// Obtained with [`Logger::handle`](crate::Logger::handle). The handle stays valid
+/// after the logger has been installed globally.
+#[derive(Clone)]
+pub struct Handle {
+    pub(crate) overrides: Arc<LevelOverrides>,
+}
+
+impl Handle {
+    /// Temporarily sets the level for `module` and its submodules.
+    ///
+    /// The override takes precedence over the configured filter directives and is
+    /// removed again when the returned guard is dropped. If needed, the global
+    /// maximum level is raised for the lifetime of the guard and restored
+    /// afterwards.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hilog::Builder;
+    /// use log::LevelFilter;
+    ///
+    /// let logger = Builder::new().filter_level(LevelFilter::Info).build();
+    /// let handle = logger.handle();
+    ///
+    /// {
+    ///     let _guard = handle.scoped_level("my_app::net", LevelFilter::Trace);
+    ///     // `my_app::net` logs at trace level here.
+    /// }
+    /// // Back to the configured filter.
+    /// ```
+    pub fn scoped_level(&self, module: &str, level: LevelFilter) -> LevelGuard {
+        let id = self.overrides.push(module, level);
+        LevelGuard {
+            overrides: Arc::clone(&self.overrides),
+            id,
+        }
+    }
+}
+
+/// Restores the previous filter of a module when dropped.
+///
+/// Created by [`Handle::scoped_level`].
+#[must_use = "the level override is removed when the guard is dropped"]
+pub struct LevelGuard {
+    overrides: Arc<LevelOverrides>,
+    id: u64,
+}
+
+impl Drop for LevelGuard {
+    fn drop(&mut self) {
+        self.overrides.remove(self.id);
+    }
+}
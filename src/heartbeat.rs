@@ -0,0 +1,82 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use std::sync::atomic::Ordering;
+use crate::sys::LogLevel;
+use log::Level;
+use crate::{HilogWriter, LogDomain, Tag};
+
+/// The shortest interval between two heartbeats.
+pub(crate) const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The configuration of a heartbeat, until the logger is built.
+pub(crate) struct Heartbeat {
+    pub(crate) interval: Duration,
+    pub(crate) level: Level,
+    pub(crate) tag: Tag,
+    pub(crate) state: Arc<State>,
+}
+
+#[derive(Default)]
+pub(crate) struct State {
+    stopped: Mutex<bool>,
+    wakeup: Condvar,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Heartbeat {
+    /// Spawns the thread printing the heartbeat.
+    pub(crate) fn start(self, writer: Arc<HilogWriter>, domain: LogDomain, level: LogLevel) {
+        let state = Arc::clone(&self.state);
+        let spawned = thread::Builder::new()
+            .name("hilog-heartbeat".into())
+            .spawn(move || self.run(&writer, domain, level));
+
+        if let Ok(thread) = spawned {
+            *state.thread.lock().unwrap_or_else(|e| e.into_inner()) = Some(thread);
+        }
+    }
+
+    fn run(self, writer: &HilogWriter, domain: LogDomain, level: LogLevel) {
+        let mut stopped = self.state.stopped.lock().unwrap_or_else(|e| e.into_inner());
+        while !*stopped {
+            // Don't keep `stop` waiting while hilog is slow or retried.
+            drop(stopped);
+            if crate::ENABLED.load(Ordering::Relaxed) {
+                let _ = writer.print_cstr(c"alive", level, domain, self.tag.as_c_str());
+            }
+
+            stopped = self.state.stopped.lock().unwrap_or_else(|e| e.into_inner());
+            stopped = self
+                .state
+                .wakeup
+                .wait_timeout_while(stopped, self.interval, |stopped| !*stopped)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+}
+
+/// Stops the heartbeat started with [`Builder::heartbeat`](crate::Builder::heartbeat).
+///
+/// Dropping the handle leaves the heartbeat running for the rest of the process.
+#[derive(Clone)]
+pub struct HeartbeatHandle {
+    pub(crate) state: Arc<State>,
+}
+
+impl HeartbeatHandle {
+    /// Stops the heartbeat and waits for its thread to exit.
+    ///
+    /// Does nothing if the heartbeat was already stopped, or if the logger was
+    /// never built.
+    pub fn stop(&self) {
+        *self.state.stopped.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        self.state.wakeup.notify_all();
+
+        let thread = self.state.thread.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(thread) = thread {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use std::fmt;
+
+/// Renders bytes as lowercase hex, e.g. `12ff00`.
+///
+/// Created by [`hex`].
+#[derive(Clone, Copy, Debug)]
+pub struct Hex<'a> {
+    bytes: &'a [u8],
+    max_len: Option<usize>,
+}
+
+/// Wraps a byte slice so that it is displayed as compact lowercase hex.
+///
+/// # Examples
+///
+/// ```
+/// let buf = [0x12, 0xff, 0x00];
+/// assert_eq!(hilog::hex(&buf).to_string(), "12ff00");
+/// assert_eq!(hilog::hex(&buf).max_len(2).to_string(), "12ff…");
+///
+/// log::debug!("received {}", hilog::hex(&buf));
+/// ```
+pub fn hex(bytes: &[u8]) -> Hex<'_> {
+    Hex { bytes, max_len: None }
+}
+
+impl<'a> Hex<'a> {
+    /// Renders at most `max_len` bytes, followed by `…` if bytes were left out.
+    pub fn max_len(mut self, max_len: usize) -> Self {
+        self.max_len = Some(max_len);
+        self
+    }
+}
+
+impl<'a> fmt::Display for Hex<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let len = self.max_len.map_or(self.bytes.len(), |max| max.min(self.bytes.len()));
+        for byte in &self.bytes[..len] {
+            write!(f, "{:02x}", byte)?;
+        }
+        if len < self.bytes.len() {
+            f.write_str("…")?;
+        }
+        Ok(())
+    }
+}
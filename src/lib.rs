@@ -4,15 +4,85 @@
 //! It's partially based on [`env_logger`], in particular the filtering
 //! is compatible with [`env_logger`].
 //!
+//! [`Builder`] provides the configuration methods of `env_logger::Builder`
+//! under the same names, e.g. [`Builder::parse_filters`] and
+//! [`Builder::filter_module`], so that most code migrating from [`env_logger`]
+//! only needs to change the import.
+//!
+//! Records are printed as public arguments of hilog. A record whose target
+//! starts with `private::` is printed as a private argument instead, which
+//! hilog redacts unless the device is in debug mode, e.g.
+//! `log::info!(target: "private::auth", "token {}", token)`. The marker is not
+//! part of a tag derived from the target, nor of the target written by the
+//! formats of this crate.
+//!
+//! Similarly, a target starting with `domain:NNNN::` prints the record to
+//! domain `NNNN`, given in decimal or as hexadecimal with a `0x` prefix, e.g.
+//! `log::info!(target: "domain:0x3200::net", "connected")`. Records without
+//! such a prefix, or with a malformed one, use the domain of the logger. The
+//! prefix follows a `private::` marker and is removed from the target like it.
+//!
 //! [`env_logger`]: https://docs.rs/env_logger/latest/env_logger/
+mod auto;
+mod context;
+mod group;
+mod handle;
+mod heartbeat;
+mod hex;
+mod loggable;
+mod macros;
 mod ohfmt;
+mod rate_limit;
+#[cfg(feature = "test-util")]
+mod scoped;
+mod sink;
+mod stats;
+mod sys;
+mod tag;
 
-use std::cell::RefCell;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
 use std::ffi::{CStr, CString};
-use std::io;
-use hilog_sys::{LogLevel, LogType, OH_LOG_IsLoggable, OH_LOG_Print};
-use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
-use crate::ohfmt::{Buffer, HilogFormatter, TimestampPrecision};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use std::time::{Duration, SystemTime};
+pub use env_filter::ParseError as FilterParseError;
+pub use crate::sys::{LogLevel, LogType};
+pub use crate::auto::auto_logger;
+pub use crate::context::{push_context, set_correlation_id, ContextGuard};
+pub use crate::group::LogGroup;
+pub use crate::handle::{Handle, LevelGuard};
+pub use crate::heartbeat::HeartbeatHandle;
+pub use crate::hex::{hex, Hex};
+pub use crate::sink::{Sink, SinkRecord};
+#[cfg(feature = "test-util")]
+pub use crate::sink::{set_test_sink, TestSinkFn};
+#[cfg(feature = "test-util")]
+pub use crate::scoped::{scoped, ScopedGuard};
+pub use crate::stats::LoggerStats;
+pub use crate::tag::{Tag, TagError};
+use crate::handle::LevelOverrides;
+use crate::heartbeat::Heartbeat;
+use crate::loggable::LoggabilityCache;
+use crate::rate_limit::RateLimit;
+use crate::ohfmt::builder::LevelStyle;
+use crate::ohfmt::ContextFormat;
+// Used by the macros of this crate, not public API.
+#[doc(hidden)]
+pub mod __private {
+    pub use log;
+
+    pub const fn is_valid_tag(tag: &str) -> bool {
+        crate::tag::is_valid(tag)
+    }
+}
+
+pub use crate::ohfmt::{FileMode, FormatContext, HilogFormatter, TimestampPrecision, WriteStyle};
 
 /// Service domain of logs
 ///
@@ -22,6 +92,26 @@ use crate::ohfmt::{Buffer, HilogFormatter, TimestampPrecision};
 pub struct LogDomain(u16);
 
 impl LogDomain {
+    /// The lowest domain.
+    pub const MIN: LogDomain = LogDomain(0);
+
+    /// The highest domain.
+    pub const MAX: LogDomain = LogDomain(0xFFFF);
+
+    /// The highest domain of the range apps should avoid.
+    ///
+    /// OpenHarmony doesn't reserve app domains formally, but the lowest ones,
+    /// starting with the default domain `0`, are used by frameworks, libraries
+    /// and samples, so logs in these domains are easily mixed up. Domains above
+    /// this one are safe to use, see [`LogDomain::is_reserved`].
+    pub const SYSTEM_RESERVED_MAX: LogDomain = LogDomain(0x00FF);
+
+    /// The first domain of system components.
+    ///
+    /// System components of OpenHarmony log with 32 bit domains from
+    /// `0xD000000` upwards, which are outside of the range of a `LogDomain`,
+    /// so they never collide with the domain of an app.
+    pub const SYSTEM_DOMAIN_BASE: u32 = 0xD00_0000;
 
     /// Creates a new LogDomain
     ///
@@ -29,235 +119,1679 @@ impl LogDomain {
     pub fn new(domain: u16) -> Self {
         Self(domain)
     }
+
+    /// Creates a new LogDomain from a value that may be out of range, e.g. one read
+    /// from a configuration file.
+    ///
+    /// Returns `None` if `domain` is above [`LogDomain::MAX`], which includes
+    /// the domains of system components.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hilog::LogDomain;
+    ///
+    /// assert_eq!(LogDomain::try_new(0x3200), Some(LogDomain::new(0x3200)));
+    /// assert_eq!(LogDomain::try_new(LogDomain::SYSTEM_DOMAIN_BASE), None);
+    /// ```
+    pub fn try_new(domain: u32) -> Option<Self> {
+        u16::try_from(domain).ok().map(Self)
+    }
+
+    /// Whether the domain is in the range up to [`LogDomain::SYSTEM_RESERVED_MAX`],
+    /// which apps should avoid.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hilog::LogDomain;
+    ///
+    /// assert!(LogDomain::default().is_reserved());
+    /// assert!(!LogDomain::new(0x3200).is_reserved());
+    /// ```
+    pub fn is_reserved(self) -> bool {
+        self <= Self::SYSTEM_RESERVED_MAX
+    }
 }
 
 
-fn hilog_log(log_type: LogType, level: LogLevel, domain: LogDomain, tag: &CStr, msg: &CStr) {
-    let _res = unsafe {
-        OH_LOG_Print(
-            log_type,
-            level,
-            domain.0.into(),
-            tag.as_ptr(),
-            c"%{public}s".as_ptr(),
-            msg.as_ptr()
-        )
-    };
+/// Log type for the kernel message buffer.
+///
+/// The NDK headers only expose [`LogType::LOG_APP`], but hilog also accepts
+/// `LOG_KMSG` for system components. Writing to it requires system privileges;
+/// regular applications will have their writes rejected, which is reported as
+/// an error by the writer.
+pub const LOG_KMSG: LogType = LogType(4);
+
+/// Capacity the formatting buffers are shrunk to with [`Builder::shrink_buffers`].
+const SHRUNK_BUFFER_CAPACITY: usize = 1024;
+
+type LevelMapFn = Arc<dyn Fn(Level) -> LogLevel + Sync + Send>;
+type LogTypeFn = Box<dyn Fn(&Record<'_>) -> LogType + Sync + Send>;
+type MirrorWhenFn = Box<dyn Fn(&Record<'_>) -> bool + Sync + Send>;
+type GateFn = Box<dyn Fn() -> bool + Sync + Send>;
+
+/// Tag of the session markers, see [`Logger::mark_session_start`].
+const SESSION_TAG: &CStr = c"hilog-session";
+
+/// Prefix of targets whose records are printed with `%{private}s`.
+const PRIVATE_TARGET_PREFIX: &str = "private::";
+
+/// Prefix of targets selecting the domain of a record, followed by the domain and `::`.
+const DOMAIN_TARGET_PREFIX: &str = "domain:";
+
+fn hilog_log(log_type: LogType, level: LogLevel, domain: LogDomain, tag: &CStr, msg: &CStr, private: bool) -> io::Result<()> {
+    // hilog redacts private arguments unless the device is in debug mode.
+    let format = if private { c"%{private}s" } else { c"%{public}s" };
+    let res = sys::print(log_type, level, domain.0.into(), tag, format, msg);
+    if res < 0 {
+        return Err(io::Error::other(format!("OH_LOG_Print failed with {res}")));
+    }
+    Ok(())
 }
 
-#[derive(Default)]
 pub struct Builder {
     filter: env_filter::Builder,
+    directives: Vec<(Option<String>, LevelFilter)>,
     log_domain: LogDomain,
     format: ohfmt::builder::Builder,
     writer: ohfmt::writer::Builder,
+    format_fallback: bool,
+    // Indexed by `Level as usize - 1`.
+    level_formats: [Option<HilogFormatFn>; 5],
+    dual_format: Option<HilogFormatFn>,
+    tag: Option<Tag>,
+    default_tag: Tag,
+    level_map: Option<LevelMapFn>,
+    log_type_fn: Option<LogTypeFn>,
+    loggability_cache_ttl: Duration,
+    domain_levels: Vec<(LogDomain, LevelFilter)>,
+    normalize_tags: bool,
+    use_message_filters: bool,
+    tag_from_target: bool,
+    tag_map: Vec<(String, Tag)>,
+    domain_routes: Vec<(String, LogDomain)>,
+    always_allow_level: Option<Level>,
+    global_rate_limit: Option<(u64, Duration)>,
+    rate_limit_only_below: Option<Level>,
+    mirror_domains: Vec<LogDomain>,
+    mirror_when: Option<MirrorWhenFn>,
+    gate: Option<GateFn>,
+    #[cfg(feature = "backtrace")]
+    backtrace_on_error: bool,
+    shrink_buffers: bool,
+    buffer_capacity_hint: usize,
+    heartbeat: Option<Heartbeat>,
+    internal_tag: Tag,
+    internal_domain: Option<LogDomain>,
+    #[cfg(feature = "api-15")]
+    sync_native_level: bool,
+    startup_banner: Option<String>,
+    // Set by `build_filter`, which consumes the filter builder, or `with_filter`.
+    built_filter: Option<env_filter::Filter>,
+    // Whether `built_filter` was passed in, so its directives are unknown.
+    prebuilt_filter: bool,
     built: bool,
 }
 
+impl Default for Builder {
+    fn default() -> Self {
+        Builder {
+            filter: Default::default(),
+            directives: Vec::new(),
+            log_domain: Default::default(),
+            format: Default::default(),
+            writer: Default::default(),
+            format_fallback: true,
+            level_formats: Default::default(),
+            dual_format: None,
+            tag: None,
+            default_tag: Tag::new("rust").expect("valid default tag"),
+            level_map: None,
+            log_type_fn: None,
+            loggability_cache_ttl: Duration::from_secs(1),
+            domain_levels: Vec::new(),
+            normalize_tags: true,
+            use_message_filters: false,
+            tag_from_target: false,
+            tag_map: Vec::new(),
+            domain_routes: Vec::new(),
+            always_allow_level: None,
+            global_rate_limit: None,
+            rate_limit_only_below: None,
+            mirror_domains: Vec::new(),
+            mirror_when: None,
+            gate: None,
+            #[cfg(feature = "backtrace")]
+            backtrace_on_error: false,
+            shrink_buffers: false,
+            buffer_capacity_hint: 0,
+            heartbeat: None,
+            internal_tag: Tag::new("hilog-internal").expect("valid internal tag"),
+            internal_domain: None,
+            #[cfg(feature = "api-15")]
+            sync_native_level: false,
+            startup_banner: None,
+            built_filter: None,
+            prebuilt_filter: false,
+            built: false,
+        }
+    }
+}
+
 impl Builder {
     pub fn new() -> Builder {
         Default::default()
     }
 
 
-    /// Sets the Service domain for the logs
+    /// Sets the hilog log type.
     ///
-    /// Users can set a custom domain, which allows filtering by hilogd.
-    pub fn set_domain(&mut self, domain: LogDomain) -> &mut Self {
-        self.log_domain = domain;
+    /// Defaults to [`LogType::LOG_APP`], which is the only type available to
+    /// third-party applications. System components may use [`LOG_KMSG`].
+    pub fn log_type(&mut self, log_type: LogType) -> &mut Self {
+        self.writer.log_type = log_type;
         self
     }
 
-    /// Adds a directive to the filter for a specific module.
+    /// Selects the hilog log type for each record.
+    ///
+    /// The closure is called for every record that passes the filters. When
+    /// unset, all records use the type set with [`Builder::log_type`]. Messages
+    /// emitted by the logger itself, such as the startup banner, always use that
+    /// type as well.
     ///
     /// # Examples
     ///
-    /// Only include messages for info and above for logs in `path::to::module`:
+    /// Send records of the `audit` target to the kernel message buffer:
     ///
     /// ```
-    /// use hilog::Builder;
-    /// use log::LevelFilter;
+    /// use hilog::{Builder, LogType, LOG_KMSG};
     ///
     /// let mut builder = Builder::new();
     ///
-    /// builder.filter_module("path::to::module", LevelFilter::Info);
+    /// builder.log_type_fn(|record| match record.target() {
+    ///     "audit" => LOG_KMSG,
+    ///     _ => LogType::LOG_APP,
+    /// });
     /// ```
-    pub fn filter_module(&mut self, module: &str, level: LevelFilter) -> &mut Self {
-        self.filter.filter_module(module, level);
+    pub fn log_type_fn<F>(&mut self, log_type_fn: F) -> &mut Self
+    where
+        F: Fn(&Record<'_>) -> LogType + Sync + Send + 'static,
+    {
+        self.log_type_fn = Some(Box::new(log_type_fn));
         self
     }
 
-    /// Adds a directive to the filter for all modules.
+    /// Sets a fixed tag for all records.
     ///
-    /// # Examples
+    /// By default the tag is derived from the module path of each record.
     ///
-    /// Only include messages for info and above for logs globally:
+    /// # Errors
+    ///
+    /// Fails if `tag` can not be converted into a valid [`Tag`].
+    ///
+    /// # Examples
     ///
     /// ```
     /// use hilog::Builder;
-    /// use log::LevelFilter;
     ///
     /// let mut builder = Builder::new();
     ///
-    /// builder.filter_level(LevelFilter::Info);
+    /// builder.tag("MyApp").expect("valid tag");
     /// ```
-    pub fn filter_level(&mut self, level: LevelFilter) -> &mut Self {
-        self.filter.filter_level(level);
+    pub fn tag<T>(&mut self, tag: T) -> Result<&mut Self, T::Error>
+    where
+        T: TryInto<Tag>,
+    {
+        self.tag = Some(tag.try_into()?);
+        Ok(self)
+    }
+
+    /// Sets the tag used for records without a module path and target.
+    ///
+    /// Defaults to `"rust"`, so that every entry has a non-empty tag.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `tag` can not be converted into a valid [`Tag`].
+    pub fn default_tag<T>(&mut self, tag: T) -> Result<&mut Self, T::Error>
+    where
+        T: TryInto<Tag>,
+    {
+        self.default_tag = tag.try_into()?;
+        Ok(self)
+    }
+
+    /// Whether to strip synthetic segments such as `{{closure}}` from derived tags.
+    ///
+    /// Module paths produced in some macro contexts contain segments like
+    /// `{{closure}}` or `{{constructor}}`, which make for unwieldy tags. When
+    /// enabled, such segments are removed from tags derived from the module path
+    /// or target, e.g. `my_app::{{closure}}` becomes `my_app`. Enabled by default;
+    /// a fixed tag is never changed.
+    pub fn normalize_tags(&mut self, normalize: bool) -> &mut Self {
+        self.normalize_tags = normalize;
         self
     }
 
-    /// Adds filters to the logger.
+    /// Whether to derive the tag from the target instead of the module path.
     ///
-    /// The given module (if any) will log at most the specified level provided.
-    /// If no module is provided then the filter will apply to all log messages.
+    /// The target of a record is its module path unless it was set explicitly,
+    /// e.g. with `info!(target: "Net", ...)` or [`hilog_tagged!`], so this
+    /// makes such targets show up as the hilog tag. Records without a target
+    /// fall back to the module path. Disabled by default; a fixed tag set with
+    /// [`Builder::tag`] takes precedence.
+    pub fn tag_from_target(&mut self, from_target: bool) -> &mut Self {
+        self.tag_from_target = from_target;
+        self
+    }
+
+    /// Maps targets and module paths to fixed tags.
     ///
-    /// # Examples
+    /// Each entry is a prefix, matched like the module of a filter directive, and
+    /// the tag for records below it. The longest prefix matching the target or,
+    /// failing that, the module path of a record determines its tag, e.g.
+    /// `("my_app::net", "Net")` tags records of `my_app::net::http` with `Net`.
+    /// Records without a match derive their tag as usual. This keeps the tags
+    /// stable when modules are moved. A fixed tag set with [`Builder::tag`] takes
+    /// precedence.
     ///
-    /// Only include messages for info and above for logs in `path::to::module`:
+    /// # Errors
+    ///
+    /// Fails if one of the tags is not a valid [`Tag`], in which case none of
+    /// the entries are added.
+    ///
+    /// # Examples
     ///
     /// ```
     /// use hilog::Builder;
-    /// use log::LevelFilter;
     ///
     /// let mut builder = Builder::new();
     ///
-    /// builder.filter(Some("path::to::module"), LevelFilter::Info);
+    /// builder.tag_map(&[("my_app::net", "Net"), ("my_app::db", "Db")]).unwrap();
     /// ```
-    pub fn filter(&mut self, module: Option<&str>, level: LevelFilter) -> &mut Self {
-        self.filter.filter(module, level);
-        self
+    pub fn tag_map(&mut self, map: &[(&str, &str)]) -> Result<&mut Self, TagError> {
+        let map = map
+            .iter()
+            .map(|&(prefix, tag)| Ok((prefix.to_owned(), Tag::new(tag)?)))
+            .collect::<Result<Vec<_>, TagError>>()?;
+        self.tag_map.extend(map);
+        Ok(self)
     }
 
-    /// Sets the format function for formatting the log output.
+    /// Redirects the records of a subsystem to a dedicated domain and tag.
     ///
-    /// This function is called on each record logged and should format the
-    /// log record and output it to the given [`HilogFormatter`].
+    /// Records whose target or module path is `target_prefix` or below it, as with
+    /// [`Builder::tag_map`], are printed to `domain` with `tag`. This isolates the
+    /// logs of a noisy subsystem in their own bucket. A `domain:NNNN::` target
+    /// prefix and a fixed tag set with [`Builder::tag`] take precedence.
     ///
-    /// The format function is expected to output the string directly to the
-    /// `HilogFormatter` so that implementations can use the [`std::fmt`] macros
-    /// to format and output without intermediate heap allocations. The default
-    /// `env_logger` HilogFormatter takes advantage of this.
+    /// # Errors
     ///
-    /// When the `color` feature is enabled, styling via ANSI escape codes is supported and the
-    /// output will automatically respect [`Builder::write_style`].
+    /// Fails if `tag` can not be converted into a valid [`Tag`].
     ///
     /// # Examples
     ///
-    /// Use a custom format to write only the log message:
-    ///
     /// ```
-    /// use std::io::Write;
-    /// use hilog::Builder;
+    /// use hilog::{Builder, LogDomain};
     ///
     /// let mut builder = Builder::new();
     ///
-    /// builder.format(|buf, record| writeln!(buf, "{}", record.args()));
+    /// builder.route("my_app::sync", LogDomain::new(0x3201), "Sync").unwrap();
     /// ```
-    ///
-    /// [`HilogFormatter`]: ohfmt/struct.HilogFormatter.html
-    /// [`String`]: https://doc.rust-lang.org/stable/std/string/struct.String.html
-    /// [`std::fmt`]: https://doc.rust-lang.org/std/fmt/index.html
-    pub fn format<F>(&mut self, format: F) -> &mut Self
+    pub fn route<T>(&mut self, target_prefix: &str, domain: LogDomain, tag: T) -> Result<&mut Self, T::Error>
     where
-        F: Fn(&mut HilogFormatter, &Record<'_>) -> io::Result<()> + Sync + Send + 'static,
+        T: TryInto<Tag>,
     {
-        self.format.custom_format = Some(Box::new(format));
-        self
+        let tag = tag.try_into()?;
+        self.tag_map.push((target_prefix.to_owned(), tag));
+        self.domain_routes.push((target_prefix.to_owned(), domain));
+        Ok(self)
     }
 
-    /// Use the default format.
+    /// Sets the tag of diagnostics emitted by the logger itself.
     ///
-    /// This method will clear any custom format set on the builder.
-    pub fn default_format(&mut self) -> &mut Self {
-        self.format = Default::default();
-        self
+    /// The logger reports some conditions on its own, e.g. dropped re-entrant
+    /// log calls or records dropped by the rate limit. These use a tag of their
+    /// own, so that they can't be mistaken for logs of the application. Defaults
+    /// to `"hilog-internal"`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `tag` can not be converted into a valid [`Tag`].
+    pub fn internal_tag<T>(&mut self, tag: T) -> Result<&mut Self, T::Error>
+    where
+        T: TryInto<Tag>,
+    {
+        self.internal_tag = tag.try_into()?;
+        Ok(self)
     }
 
-    /// Whether or not to write the level in the default format.
-    pub fn format_level(&mut self, write: bool) -> &mut Self {
-        self.format.format_level = write;
+    /// Sets the domain of diagnostics emitted by the logger itself.
+    ///
+    /// Defaults to the domain of the logger, see [`Builder::internal_tag`].
+    pub fn internal_domain(&mut self, domain: LogDomain) -> &mut Self {
+        self.internal_domain = Some(domain);
         self
     }
 
-    /// Whether or not to write the module path in the default format.
-    pub fn format_module_path(&mut self, write: bool) -> &mut Self {
-        self.format.format_module_path = write;
+    /// Sets how `log` levels are mapped to hilog levels.
+    ///
+    /// By default each level maps to the hilog level of the same name, except
+    /// for [`Level::Trace`], which has no hilog equivalent and maps to
+    /// [`LogLevel::LOG_DEBUG`].
+    ///
+    /// # Examples
+    ///
+    /// Report warnings as errors:
+    ///
+    /// ```
+    /// use hilog::{Builder, LogLevel};
+    /// use log::Level;
+    ///
+    /// let mut builder = Builder::new();
+    ///
+    /// builder.level_map(|level| match level {
+    ///     Level::Warn => LogLevel::LOG_ERROR,
+    ///     level => level.into(),
+    /// });
+    /// ```
+    pub fn level_map<F>(&mut self, level_map: F) -> &mut Self
+    where
+        F: Fn(Level) -> LogLevel + Sync + Send + 'static,
+    {
+        self.level_map = Some(Arc::new(level_map));
         self
     }
 
-    /// Whether or not to write the target in the default format.
-    pub fn format_target(&mut self, write: bool) -> &mut Self {
-        self.format.format_target = write;
+    /// Sets for how long the results of the hilog loggability check are cached.
+    ///
+    /// Before a record is formatted, hilog is asked whether the domain, tag and
+    /// level are loggable at all. To avoid crossing the FFI boundary for every
+    /// record, the answer is cached per domain, tag and level. Defaults to one
+    /// second; a duration of zero disables the cache.
+    pub fn loggability_cache_ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.loggability_cache_ttl = ttl;
         self
     }
 
-    /// Configures the amount of spaces to use to indent multiline log records.
-    /// A value of `None` disables any kind of indentation.
-    pub fn format_indent(&mut self, indent: Option<usize>) -> &mut Self {
-        self.format.format_indent = indent;
+    /// Limits the number of records logged per time window.
+    ///
+    /// At most `max_records` records that passed the filters are logged in each
+    /// window of `per`, regardless of their content. Further records in the same
+    /// window are dropped, and a single warning with the number of dropped
+    /// records is logged along with the first record of a later window. This
+    /// protects hilogd from log storms.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use hilog::Builder;
+    ///
+    /// let mut builder = Builder::new();
+    ///
+    /// builder.global_rate_limit(1000, Duration::from_secs(1));
+    /// ```
+    pub fn global_rate_limit(&mut self, max_records: u64, per: Duration) -> &mut Self {
+        self.global_rate_limit = Some((max_records, per));
         self
     }
 
-    /// Configures if timestamp should be included and in what precision.
-    pub fn format_timestamp(&mut self, timestamp: Option<TimestampPrecision>) -> &mut Self {
-        self.format.format_timestamp = timestamp;
+    /// Only applies the rate limit to records less severe than `level`.
+    ///
+    /// Records of `level` and more severe ones always pass the limit set with
+    /// [`Builder::global_rate_limit`] and don't use it up, e.g. with
+    /// [`Level::Info`] only debug and trace records are throttled, so that noise
+    /// control never suppresses warnings or errors.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use hilog::Builder;
+    /// use log::Level;
+    ///
+    /// let mut builder = Builder::new();
+    ///
+    /// builder
+    ///     .global_rate_limit(100, Duration::from_secs(1))
+    ///     .rate_limit_only_below(Level::Info);
+    /// ```
+    pub fn rate_limit_only_below(&mut self, level: Level) -> &mut Self {
+        self.rate_limit_only_below = Some(level);
         self
     }
 
-    /// Configures the timestamp to use second precision.
-    pub fn format_timestamp_secs(&mut self) -> &mut Self {
-        self.format_timestamp(Some(TimestampPrecision::Seconds))
+    /// Sets the Service domain for the logs
+    ///
+    /// Users can set a custom domain, which allows filtering by hilogd. Pick one
+    /// above [`LogDomain::SYSTEM_RESERVED_MAX`]; in debug builds a reserved domain
+    /// is reported on stderr.
+    pub fn set_domain(&mut self, domain: LogDomain) -> &mut Self {
+        if cfg!(debug_assertions) && domain.is_reserved() {
+            eprintln!(
+                "hilog: domain {:#06x} is reserved, pick one above {:#06x}",
+                domain.0,
+                LogDomain::SYSTEM_RESERVED_MAX.0
+            );
+        }
+        self.log_domain = domain;
+        self
     }
 
-    /// Configures the timestamp to use millisecond precision.
-    pub fn format_timestamp_millis(&mut self) -> &mut Self {
-        self.format_timestamp(Some(TimestampPrecision::Millis))
+    /// Additionally prints records to each of `domains`.
+    ///
+    /// Every record is printed to its own domain first, then once more to each
+    /// mirror domain, so that monitoring filters on several domains catch it.
+    /// Each copy is a separate call into hilog and subject to the level of its
+    /// domain and the hilog loggability check. Use [`Builder::mirror_when`] to
+    /// only mirror some records.
+    ///
+    /// # Examples
+    ///
+    /// Mirror errors to a monitoring domain:
+    ///
+    /// ```
+    /// use hilog::{Builder, LogDomain};
+    /// use log::Level;
+    ///
+    /// let mut builder = Builder::new();
+    ///
+    /// builder
+    ///     .mirror_domains(&[LogDomain::new(0x5678)])
+    ///     .mirror_when(|record| record.level() == Level::Error);
+    /// ```
+    pub fn mirror_domains(&mut self, domains: &[LogDomain]) -> &mut Self {
+        self.mirror_domains = domains.to_vec();
+        self
     }
 
-    /// Configures the timestamp to use microsecond precision.
-    pub fn format_timestamp_micros(&mut self) -> &mut Self {
-        self.format_timestamp(Some(TimestampPrecision::Micros))
+    /// Sets which records are printed to the domains set with [`Builder::mirror_domains`].
+    ///
+    /// By default all records are mirrored.
+    pub fn mirror_when<F>(&mut self, mirror_when: F) -> &mut Self
+    where
+        F: Fn(&Record<'_>) -> bool + Sync + Send + 'static,
+    {
+        self.mirror_when = Some(Box::new(mirror_when));
+        self
     }
 
-    /// Configures the timestamp to use nanosecond precision.
-    pub fn format_timestamp_nanos(&mut self) -> &mut Self {
-        self.format_timestamp(Some(TimestampPrecision::Nanos))
+    /// Whether to append a backtrace to error records.
+    ///
+    /// The backtrace is captured with [`Backtrace::capture`], so it is only
+    /// appended if enabled with the `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE`
+    /// environment variables, and written on the lines after the formatted
+    /// record. Backtraces are often longer than [`Builder::max_formatted_len`],
+    /// which may need to be raised. Disabled by default, which costs nothing
+    /// per record.
+    ///
+    /// [`Backtrace::capture`]: std::backtrace::Backtrace::capture
+    #[cfg(feature = "backtrace")]
+    pub fn backtrace_on_error(&mut self, enabled: bool) -> &mut Self {
+        self.backtrace_on_error = enabled;
+        self
     }
 
-    /// Configures the end of line suffix.
-    pub fn format_suffix(&mut self, suffix: &'static str) -> &mut Self {
-        self.format.format_suffix = suffix;
+    /// Only logs while `gate` returns `true`.
+    ///
+    /// The gate is called for every record before any other filtering, right
+    /// after the check of [`set_enabled`], so it should be cheap. Unlike the
+    /// global switch it allows dynamic policies, e.g. logging only while a remote
+    /// configuration flag is set or only during the first minute after start.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::{Duration, Instant};
+    /// use hilog::Builder;
+    ///
+    /// let start = Instant::now();
+    /// let mut builder = Builder::new();
+    ///
+    /// builder.gate(move || start.elapsed() < Duration::from_secs(60));
+    /// ```
+    pub fn gate<F>(&mut self, gate: F) -> &mut Self
+    where
+        F: Fn() -> bool + Sync + Send + 'static,
+    {
+        self.gate = Some(Box::new(gate));
         self
     }
 
-    /// Initializes the global logger with the built env logger.
+    /// Sets the maximum level for records logged to `domain`.
     ///
-    /// This should be called early in the execution of a Rust program. Any log
-    /// events that occur before initialization will be ignored.
+    /// This is checked in addition to the filter directives: a record that
+    /// passes the directives but is more verbose than the level of its domain
+    /// is dropped. Setting a level for the same domain again replaces it.
     ///
-    /// # Errors
+    /// # Examples
     ///
-    /// This function will fail if it is called more than once, or if another
-    /// library has already initialized a global logger.
-    pub fn try_init(&mut self) -> Result<(), SetLoggerError> {
-        let logger = self.build();
-
-        let max_level = logger.filter();
-        let r = log::set_boxed_logger(Box::new(logger));
-
-        if r.is_ok() {
-            log::set_max_level(max_level);
+    /// ```
+    /// use hilog::{Builder, LogDomain};
+    /// use log::LevelFilter;
+    ///
+    /// let mut builder = Builder::new();
+    ///
+    /// builder
+    ///     .filter_level(LevelFilter::Debug)
+    ///     .domain_level(LogDomain::new(0x1234), LevelFilter::Warn);
+    /// ```
+    pub fn domain_level(&mut self, domain: LogDomain, level: LevelFilter) -> &mut Self {
+        if let Some(pos) = self.domain_levels.iter().position(|(d, _)| *d == domain) {
+            self.domain_levels[pos].1 = level;
+        } else {
+            self.domain_levels.push((domain, level));
         }
+        self
+    }
 
-        r
+    /// Whether to write the name of the process in the default format.
+    ///
+    /// hilog records the process id, but not its name, which makes it hard to
+    /// tell processes sharing a domain apart in exported logs. The name is read
+    /// once when the logger is built, from `/proc/self/comm`, and written as a
+    /// header field after the level.
+    pub fn format_process_name(&mut self, write: bool) -> &mut Self {
+        self.format.format_process_name = write;
+        self
     }
 
-    /// Initializes the global logger with the built env logger.
+    /// Sets a fixed component name written as a header field in the default format.
     ///
-    /// This should be called early in the execution of a Rust program. Any log
-    /// events that occur before initialization will be ignored.
+    /// Unlike the tag, the component is part of the message itself, so it stays
+    /// searchable in exported logs.
+    pub fn component(&mut self, component: &'static str) -> &mut Self {
+        self.format.format_component = Some(component);
+        self
+    }
+
+    /// Adds key-value pairs that the default format appends to every record.
     ///
-    /// # Panics
+    /// The pairs are written as ` key=value` in front of the context of the
+    /// thread (see [`push_context`]); if the context contains the same key, the
+    /// default is left out. Adding a key again replaces its value. The JSON
+    /// format of [`Builder::format_ndjson`] writes them into its `kv` object.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hilog::Builder;
+    ///
+    /// let mut builder = Builder::new();
+    ///
+    /// builder.default_kv(&[("service", "auth"), ("region", "eu")]);
+    /// ```
+    pub fn default_kv(&mut self, kvs: &[(&str, &str)]) -> &mut Self {
+        let defaults = &mut self.format.format_default_kv;
+        for &(key, value) in kvs {
+            match defaults.iter_mut().find(|(k, _)| k == key) {
+                Some((_, v)) => *v = value.to_owned(),
+                None => defaults.push((key.to_owned(), value.to_owned())),
+            }
+        }
+        self
+    }
+
+    /// Sets a function that returns the id of the current async task, if any.
+    ///
+    /// The id is written as a `task=<id>` header field in the default format,
+    /// which helps to correlate interleaved logs of tasks sharing a thread. The
+    /// function does the runtime specific lookup and returns `None` outside of a
+    /// task, in which case no field is written.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::cell::Cell;
+    /// use hilog::Builder;
+    ///
+    /// thread_local! {
+    ///     // Maintained by the executor while polling a task.
+    ///     static CURRENT_TASK: Cell<Option<u64>> = const { Cell::new(None) };
+    /// }
+    ///
+    /// let mut builder = Builder::new();
+    ///
+    /// builder.task_id_fn(|| CURRENT_TASK.with(Cell::get));
+    /// ```
+    pub fn task_id_fn<F>(&mut self, task_id_fn: F) -> &mut Self
+    where
+        F: Fn() -> Option<u64> + Sync + Send + 'static,
+    {
+        self.format.task_id_fn = Some(Box::new(task_id_fn));
+        self
+    }
+
+    /// Whether to write the correlation id of the thread in the default format.
+    ///
+    /// The id set with [`set_correlation_id`] is written as a header field after
+    /// the task id, e.g. `cid=4bf92f35`. The field is omitted while no id is set.
+    pub fn format_correlation_id(&mut self, write: bool) -> &mut Self {
+        self.format.format_correlation_id = write;
+        self
+    }
+
+    /// Adds a directive to the filter for a specific module.
+    ///
+    /// # Examples
+    ///
+    /// Only include messages for info and above for logs in `path::to::module`:
+    ///
+    /// ```
+    /// use hilog::Builder;
+    /// use log::LevelFilter;
+    ///
+    /// let mut builder = Builder::new();
+    ///
+    /// builder.filter_module("path::to::module", LevelFilter::Info);
+    /// ```
+    pub fn filter_module(&mut self, module: &str, level: LevelFilter) -> &mut Self {
+        self.filter(Some(module), level)
+    }
+
+    /// Adds a directive to the filter for all modules.
+    ///
+    /// # Examples
+    ///
+    /// Only include messages for info and above for logs globally:
+    ///
+    /// ```
+    /// use hilog::Builder;
+    /// use log::LevelFilter;
+    ///
+    /// let mut builder = Builder::new();
+    ///
+    /// builder.filter_level(LevelFilter::Info);
+    /// ```
+    pub fn filter_level(&mut self, level: LevelFilter) -> &mut Self {
+        self.filter(None, level)
+    }
+
+    /// Sets the global log level from a count of verbosity flags.
+    ///
+    /// This maps the number of `-v` flags of a command line, starting from
+    /// warnings, to [`Builder::filter_level`]:
+    ///
+    /// | `verbosity` | level                 |
+    /// |-------------|-----------------------|
+    /// | 0           | [`LevelFilter::Warn`]  |
+    /// | 1           | [`LevelFilter::Info`]  |
+    /// | 2           | [`LevelFilter::Debug`] |
+    /// | 3 or more   | [`LevelFilter::Trace`] |
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hilog::Builder;
+    ///
+    /// // e.g. `-vv`
+    /// let mut builder = Builder::new();
+    ///
+    /// builder.verbosity(2);
+    /// ```
+    pub fn verbosity(&mut self, verbosity: u8) -> &mut Self {
+        let level = match verbosity {
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Info,
+            2 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        };
+        self.filter_level(level)
+    }
+
+    /// Adds filters to the logger.
+    ///
+    /// The given module (if any) will log at most the specified level provided.
+    /// If no module is provided then the filter will apply to all log messages.
+    ///
+    /// # Examples
+    ///
+    /// Only include messages for info and above for logs in `path::to::module`:
+    ///
+    /// ```
+    /// use hilog::Builder;
+    /// use log::LevelFilter;
+    ///
+    /// let mut builder = Builder::new();
+    ///
+    /// builder.filter(Some("path::to::module"), LevelFilter::Info);
+    /// ```
+    pub fn filter(&mut self, module: Option<&str>, level: LevelFilter) -> &mut Self {
+        self.filter.filter(module, level);
+        self.insert_directive(module.map(|m| m.to_owned()), level);
+        self
+    }
+
+    /// Always logs records of `level` and more severe ones, regardless of the filters.
+    ///
+    /// Such records bypass the filter directives, the message filter and the
+    /// domain levels, so that e.g. errors are never lost to a restrictive
+    /// `RUST_LOG`. The hilog loggability check and [`set_enabled`] still apply.
+    ///
+    /// # Examples
+    ///
+    /// Only log debug records of `my_app::net`, but errors from everywhere:
+    ///
+    /// ```
+    /// use hilog::Builder;
+    /// use log::{Level, LevelFilter};
+    ///
+    /// let mut builder = Builder::new();
+    ///
+    /// builder
+    ///     .filter_level(LevelFilter::Off)
+    ///     .filter_module("my_app::net", LevelFilter::Debug)
+    ///     .always_allow_level(Level::Error);
+    /// ```
+    pub fn always_allow_level(&mut self, level: Level) -> &mut Self {
+        self.always_allow_level = Some(level);
+        self
+    }
+
+    /// Only logs messages matching the given regular expression.
+    ///
+    /// This uses the message filter of `env_filter`, i.e. the `/pattern` suffix of
+    /// a `RUST_LOG` style filter string: records whose formatted message does not
+    /// match `pattern` are dropped. Setting a new pattern replaces the previous one.
+    /// An invalid pattern is reported on stderr and ignored.
+    ///
+    /// This also enables [`Builder::use_message_filters`]. Note that checking the
+    /// pattern requires formatting the message of every record that passes the
+    /// level filters.
+    ///
+    /// # Examples
+    ///
+    /// Only include messages containing `connection`:
+    ///
+    /// ```
+    /// use hilog::Builder;
+    ///
+    /// let mut builder = Builder::new();
+    ///
+    /// builder.filter_message_regex("connection");
+    /// ```
+    pub fn filter_message_regex(&mut self, pattern: &str) -> &mut Self {
+        self.filter.parse(&format!("/{pattern}"));
+        self.use_message_filters = true;
+        self
+    }
+
+    /// Whether to apply the message filter when a record is logged.
+    ///
+    /// `env_filter` checks the level of a record from its metadata alone, while
+    /// the message filter set with [`Builder::filter_message_regex`] or a
+    /// `/pattern` directive needs the formatted message. When enabled, every
+    /// record that passes the level filters is formatted once more to match it
+    /// against the pattern, if a pattern is set. When disabled, which is the
+    /// default unless [`Builder::filter_message_regex`] is used, the message
+    /// filter is ignored and only the metadata is checked, which is much cheaper.
+    pub fn use_message_filters(&mut self, use_message_filters: bool) -> &mut Self {
+        self.use_message_filters = use_message_filters;
+        self
+    }
+
+    /// Parses the directives string in the same form as the `RUST_LOG`
+    /// environment variable.
+    ///
+    /// See the [`env_logger` documentation] for the syntax. Invalid directives
+    /// are reported on stderr and ignored.
+    ///
+    /// [`env_logger` documentation]: https://docs.rs/env_logger/latest/env_logger/#enabling-logging
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hilog::Builder;
+    ///
+    /// let mut builder = Builder::new();
+    ///
+    /// builder.parse_filters("info,my_app::net=trace");
+    /// ```
+    pub fn parse_filters(&mut self, filters: &str) -> &mut Self {
+        self.filter.parse(filters);
+        for (module, level) in parse_directives(filters) {
+            self.insert_directive(module, level);
+        }
+        self
+    }
+
+    /// Parses the filters from a file, e.g. one shipped with a deployment.
+    ///
+    /// The file contains directives in the same form as `RUST_LOG`, possibly
+    /// spread over several lines, which are joined with `,`. Blank lines and
+    /// everything after a `#` are ignored, except in the message filter
+    /// (`/pattern`), where `#` is part of the regular expression. The message
+    /// filter must be on the last line.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the file can not be read, or with [`io::ErrorKind::InvalidData`]
+    /// wrapping the [`FilterParseError`] if it contains an invalid directive. No
+    /// filters are added in either case.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use hilog::Builder;
+    ///
+    /// let mut builder = Builder::new();
+    ///
+    /// if let Err(e) = builder.parse_filters_from_file("/data/storage/el2/base/log.conf") {
+    ///     eprintln!("using the default filters: {e}");
+    /// }
+    /// ```
+    pub fn parse_filters_from_file<P>(&mut self, path: P) -> io::Result<&mut Self>
+    where
+        P: AsRef<Path>,
+    {
+        let contents = fs::read_to_string(path)?;
+        let filters = contents
+            .lines()
+            .map(strip_filter_comment)
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join(",");
+        validate_filter(&filters).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(self.parse_filters(&filters))
+    }
+
+    /// Parses the filters from the environment variable `env`, if it is set.
+    ///
+    /// Unlike `env_logger`, this takes the name of the variable, since the
+    /// write style of hilog can't be configured.
+    pub fn parse_env(&mut self, env: &str) -> &mut Self {
+        if let Ok(filters) = std::env::var(env) {
+            self.parse_filters(&filters);
+        }
+        self
+    }
+
+    /// Parses the filters from the `RUST_LOG` environment variable, if it is set.
+    pub fn parse_default_env(&mut self) -> &mut Self {
+        self.parse_env("RUST_LOG")
+    }
+
+    /// Sets whether the output is styled.
+    ///
+    /// Only exists for compatibility with `env_logger`, see [`WriteStyle`].
+    pub fn write_style(&mut self, _write_style: WriteStyle) -> &mut Self {
+        self
+    }
+
+    /// Sets whether the logger is used in unit tests.
+    ///
+    /// Only exists for compatibility with `env_logger`: records are always sent
+    /// to hilog, which the test harness can't capture anyway.
+    pub fn is_test(&mut self, _is_test: bool) -> &mut Self {
+        self
+    }
+
+    /// Remembers a directive so that it can later be inspected on the `Logger`.
+    ///
+    /// A directive for the same module replaces the previous one, like in `env_filter`.
+    /// Once the filter was built, directives have no effect and aren't recorded.
+    fn insert_directive(&mut self, module: Option<String>, level: LevelFilter) {
+        if self.built_filter.is_some() {
+            return;
+        }
+        if let Some(pos) = self.directives.iter().position(|(m, _)| *m == module) {
+            self.directives[pos].1 = level;
+        } else {
+            self.directives.push((module, level));
+        }
+    }
+
+    /// Sets the format function for formatting the log output.
+    ///
+    /// This function is called on each record logged and should format the
+    /// log record and output it to the given [`HilogFormatter`].
+    ///
+    /// The format function is expected to output the string directly to the
+    /// `HilogFormatter` so that implementations can use the [`std::fmt`] macros
+    /// to format and output without intermediate heap allocations. The default
+    /// `env_logger` HilogFormatter takes advantage of this.
+    ///
+    /// When the `color` feature is enabled, styling via ANSI escape codes is supported and the
+    /// output will automatically respect [`Builder::write_style`].
+    ///
+    /// # Examples
+    ///
+    /// Use a custom format to write only the log message:
+    ///
+    /// ```
+    /// use std::io::Write;
+    /// use hilog::Builder;
+    ///
+    /// let mut builder = Builder::new();
+    ///
+    /// builder.format(|buf, record| writeln!(buf, "{}", record.args()));
+    /// ```
+    ///
+    /// [`HilogFormatter`]: ohfmt/struct.HilogFormatter.html
+    /// [`String`]: https://doc.rust-lang.org/stable/std/string/struct.String.html
+    /// [`std::fmt`]: https://doc.rust-lang.org/std/fmt/index.html
+    pub fn format<F>(&mut self, format: F) -> &mut Self
+    where
+        F: Fn(&mut HilogFormatter, &Record<'_>) -> io::Result<()> + Sync + Send + 'static,
+    {
+        self.format.custom_format = Some(Box::new(format));
+        self.format.custom_context_format = None;
+        self
+    }
+
+    /// Sets the format function for records of `level`.
+    ///
+    /// Records of `level` are formatted with `format` instead of the format set
+    /// with [`Builder::format`], [`Builder::format_with_context`] or the default
+    /// format, e.g. to add the file and line only to errors. Setting another
+    /// format for the same level replaces the previous one.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::io::Write;
+    /// use hilog::Builder;
+    /// use log::Level;
+    ///
+    /// let mut builder = Builder::new();
+    ///
+    /// builder.format_for_level(Level::Error, |buf, record| {
+    ///     let file = record.file().unwrap_or("?");
+    ///     let line = record.line().unwrap_or(0);
+    ///     write!(buf, "[{}:{}] {}", file, line, record.args())
+    /// });
+    /// ```
+    pub fn format_for_level<F>(&mut self, level: Level, format: F) -> &mut Self
+    where
+        F: Fn(&mut HilogFormatter, &Record<'_>) -> io::Result<()> + Sync + Send + 'static,
+    {
+        self.level_formats[level as usize - 1] = Some(Box::new(format));
+        self
+    }
+
+    /// Sets the format function for formatting the log output, with access to
+    /// the [`FormatContext`] of the record.
+    ///
+    /// Like [`Builder::format`], but the closure also receives the domain and
+    /// the tag the record is printed with, which this crate resolves, and the
+    /// timestamp of the record taken from [`Builder::timestamp_source`]. This
+    /// replaces any format set with [`Builder::format`]. If the record is
+    /// mirrored to other domains, the context refers to its own domain.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::io::Write;
+    /// use hilog::Builder;
+    ///
+    /// let mut builder = Builder::new();
+    ///
+    /// builder.format_with_context(|buf, record, ctx| {
+    ///     write!(buf, "{:?} {:?}: {}", ctx.domain(), ctx.tag(), record.args())
+    /// });
+    /// ```
+    pub fn format_with_context<F>(&mut self, format: F) -> &mut Self
+    where
+        F: Fn(&mut HilogFormatter, &Record<'_>, &FormatContext<'_>) -> io::Result<()> + Sync + Send + 'static,
+    {
+        self.format.custom_context_format = Some(Box::new(format));
+        self.format.custom_format = None;
+        self
+    }
+
+    /// Writes every record twice, formatted with `human` and with `machine`.
+    ///
+    /// This is for pipelines that are both read by people and parsed by tools,
+    /// e.g. with a JSON `machine` format. `human` replaces the format set with
+    /// [`Builder::format`]. Both lines are printed one after the other and start
+    /// with the same sequence id, e.g. `#42 `, so tools can pair them. This
+    /// doubles the cost of writing every record. Records logged in a
+    /// [`Logger::group`] are only written with `human`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::io::Write;
+    /// use hilog::Builder;
+    ///
+    /// let mut builder = Builder::new();
+    ///
+    /// builder.dual_format(
+    ///     |buf, record| write!(buf, "[{}] {}", record.level(), record.args()),
+    ///     |buf, record| write!(buf, r#"{{"level":"{}","msg":"{}"}}"#, record.level(), record.args()),
+    /// );
+    /// ```
+    pub fn dual_format<H, M>(&mut self, human: H, machine: M) -> &mut Self
+    where
+        H: Fn(&mut HilogFormatter, &Record<'_>) -> io::Result<()> + Sync + Send + 'static,
+        M: Fn(&mut HilogFormatter, &Record<'_>) -> io::Result<()> + Sync + Send + 'static,
+    {
+        self.format(human);
+        self.dual_format = Some(Box::new(machine));
+        self
+    }
+
+    /// Use the default format.
+    ///
+    /// This method will clear any custom format set on the builder, including
+    /// those set with [`Builder::format_for_level`].
+    pub fn default_format(&mut self) -> &mut Self {
+        self.format = Default::default();
+        self.level_formats = Default::default();
+        self.dual_format = None;
+        self
+    }
+
+    /// Use a preset that mimics the line layout of native hilog output.
+    ///
+    /// Lines are written as the level letter, the target and the message, e.g.
+    /// `I my_app::net: connected`, without timestamp, brackets or line suffix.
+    /// This clears any custom format and the JSON or CBOR output, other settings
+    /// of the default format are kept.
+    pub fn format_hilog_native(&mut self) -> &mut Self {
+        self.format.custom_format = None;
+        self.format.custom_context_format = None;
+        self.format.format_ndjson = false;
+        #[cfg(feature = "binary")]
+        {
+            self.format.format_cbor = false;
+        }
+        self.format.format_timestamp = None;
+        self.format.format_level = true;
+        self.format.format_level_style = LevelStyle::Letter;
+        self.format.format_module_path = false;
+        self.format.format_target = true;
+        self.format.format_header_brackets = false;
+        self.format.format_header_separator = ": ";
+        self.format.format_indent = None;
+        self.format.format_suffix = "";
+        self
+    }
+
+    /// Use a preset that writes only the message.
+    ///
+    /// All header fields, the timestamp and the key-value pairs of the context
+    /// are disabled and the suffix is empty, so each hilog entry contains exactly
+    /// the formatted message. Multiline messages are not indented. This clears
+    /// any custom format.
+    pub fn format_args_only(&mut self) -> &mut Self {
+        self.format.custom_format = None;
+        self.format.custom_context_format = None;
+        self.format.format_ndjson = false;
+        #[cfg(feature = "binary")]
+        {
+            self.format.format_cbor = false;
+        }
+        self.format.format_timestamp = None;
+        self.format.format_level = false;
+        self.format.format_module_path = false;
+        self.format.format_target = false;
+        self.format.format_component = None;
+        self.format.format_process_name = false;
+        self.format.format_file = None;
+        self.format.format_delta = false;
+        self.format.format_uptime = false;
+        self.format.task_id_fn = None;
+        self.format.format_correlation_id = false;
+        self.format.format_context = false;
+        self.format.format_indent = None;
+        self.format.format_suffix = "";
+        self
+    }
+
+    /// Emits every record as a single line JSON object.
+    ///
+    /// The keys are always `ts`, `lvl`, `tag`, `msg` and `kv`, in this order, e.g.
+    /// `{"ts":"2024-02-29T12:34:56.123Z","lvl":"INFO","tag":"my_app::net","msg":"connected","kv":{"service":"auth"}}`.
+    /// The timestamp uses [`Builder::timestamp_source`] and `tag` is the module
+    /// path of the record, or its target if there is none. `kv` holds the pairs
+    /// of [`Builder::default_kv`] and of the context of the thread, see
+    /// [`push_context`]. Newlines in the
+    /// message are escaped, so each hilog entry is exactly one object. This clears
+    /// any custom format and the CBOR output.
+    pub fn format_ndjson(&mut self) -> &mut Self {
+        self.format.custom_format = None;
+        self.format.custom_context_format = None;
+        self.format.format_ndjson = true;
+        #[cfg(feature = "binary")]
+        {
+            self.format.format_cbor = false;
+        }
+        self
+    }
+
+    /// Emits records as hex encoded CBOR instead of text.
+    ///
+    /// Each message is a CBOR map with the keys `level`, `target`, `timestamp`
+    /// (milliseconds since the unix epoch, see [`Builder::timestamp_source`]),
+    /// `message` and `kvs`, a map with the pairs of [`Builder::default_kv`] and of
+    /// the context of the thread. It is hex encoded since hilog only transports
+    /// text, and can be decoded by standard CBOR tooling. This clears any custom
+    /// format and the JSON output.
+    #[cfg(feature = "binary")]
+    pub fn format_cbor(&mut self) -> &mut Self {
+        self.format.custom_format = None;
+        self.format.custom_context_format = None;
+        self.format.format_ndjson = false;
+        self.format.format_cbor = true;
+        self
+    }
+
+    /// Whether or not to write the level in the default format.
+    pub fn format_level(&mut self, write: bool) -> &mut Self {
+        self.format.format_level = write;
+        self
+    }
+
+    /// Whether to write the level as the number of the hilog level in the default format.
+    ///
+    /// The number is that of the [`LogLevel`] the record is printed with, taking
+    /// [`Builder::level_map`] into account, e.g. `4` for [`Level::Info`]. This
+    /// helps tooling that keys off the numeric level. When disabled, the level
+    /// name is written.
+    pub fn format_level_numeric(&mut self, numeric: bool) -> &mut Self {
+        self.format.format_level_style = if numeric { LevelStyle::Numeric } else { LevelStyle::Name };
+        self
+    }
+
+    /// Whether to write the level as a symbol in the default format.
+    ///
+    /// The default glyphs are `■` for errors, `▲` for warnings, `●` for info,
+    /// `○` for debug and `·` for trace records, see [`Builder::format_level_glyphs`]
+    /// to change them. Glyphs are not padded. When disabled, the level name is
+    /// written.
+    pub fn format_level_glyph(&mut self, glyph: bool) -> &mut Self {
+        self.format.format_level_style = if glyph { LevelStyle::Glyph } else { LevelStyle::Name };
+        self
+    }
+
+    /// Sets the glyphs of the levels from [`Level::Error`] to [`Level::Trace`] and writes them.
+    ///
+    /// This enables [`Builder::format_level_glyph`]. An empty glyph falls back to
+    /// the first letter of the level name, e.g. `W`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hilog::Builder;
+    ///
+    /// let mut builder = Builder::new();
+    ///
+    /// builder.format_level_glyphs(["❌", "⚠️", "ℹ️", "", ""]);
+    /// ```
+    pub fn format_level_glyphs(&mut self, glyphs: [&'static str; 5]) -> &mut Self {
+        self.format.format_level_glyphs = glyphs;
+        self.format.format_level_style = LevelStyle::Glyph;
+        self
+    }
+
+    /// Whether to pad the level name in the default format to five characters.
+    ///
+    /// Padding aligns e.g. `INFO ` with `ERROR`. Enabled by default; disable it to
+    /// avoid the trailing space in compact formats.
+    pub fn format_level_padded(&mut self, padded: bool) -> &mut Self {
+        self.format.format_level_padded = padded;
+        self
+    }
+
+    /// Whether or not to write the module path in the default format.
+    pub fn format_module_path(&mut self, write: bool) -> &mut Self {
+        self.format.format_module_path = write;
+        self
+    }
+
+    /// Whether to abbreviate the module path in the default format.
+    ///
+    /// All segments but the last are cut to their first letter, e.g.
+    /// `my_app::network::http::connection` is written as `m::n::h::connection`,
+    /// which keeps long paths within the budget of a hilog line. Only has an
+    /// effect if the module path is written.
+    pub fn format_module_abbrev(&mut self, abbrev: bool) -> &mut Self {
+        self.format.format_module_abbrev = abbrev;
+        self
+    }
+
+    /// Whether or not to write the target in the default format.
+    pub fn format_target(&mut self, write: bool) -> &mut Self {
+        self.format.format_target = write;
+        self
+    }
+
+    /// Configures if the source file and line of a record should be included, and how.
+    ///
+    /// The location is written as a header field after the target, e.g.
+    /// `src/net.rs:42` with [`FileMode::RelativeToCrate`]. Disabled by default.
+    /// Trimming the path keeps build machine specific paths out of shipped logs.
+    pub fn format_file_mode(&mut self, mode: Option<FileMode>) -> &mut Self {
+        self.format.format_file = mode;
+        self
+    }
+
+    /// Configures the amount of spaces to use to indent multiline log records.
+    /// A value of `None` disables any kind of indentation.
+    ///
+    /// The indentation is capped at 64 characters, larger values are reduced to it.
+    pub fn format_indent(&mut self, indent: Option<usize>) -> &mut Self {
+        self.format.format_indent = indent.map(|indent| indent.min(ohfmt::builder::MAX_INDENT));
+        self
+    }
+
+    /// Configures the character used to indent multiline log records.
+    ///
+    /// Continuation lines are prefixed with this character repeated as often as
+    /// configured with [`Builder::format_indent`]. Defaults to a space.
+    pub fn format_indent_char(&mut self, indent_char: char) -> &mut Self {
+        self.format.format_indent_char = indent_char;
+        self
+    }
+
+    /// Whether to collapse runs of spaces and tabs in the message to a single space.
+    ///
+    /// This keeps pretty-printed output such as `{:#?}` compact. Newlines are
+    /// preserved.
+    pub fn collapse_whitespace(&mut self, collapse: bool) -> &mut Self {
+        self.format.format_collapse_whitespace = collapse;
+        self
+    }
+
+    /// Whether to wrap the message in double quotes in the default format.
+    ///
+    /// Quotes and backslashes within the message are escaped with a backslash,
+    /// so that parsers splitting the line on `]` or the header separator can
+    /// reliably isolate the message, e.g. `[INFO  my_app] "got \"]\""`. The
+    /// header and the key-value pairs of the context are not quoted. Disabled by
+    /// default.
+    pub fn quote_message(&mut self, quote: bool) -> &mut Self {
+        self.format.format_quote_message = quote;
+        self
+    }
+
+    /// Configures if timestamp should be included and in what precision.
+    ///
+    /// Timestamps are written in RFC3339 format in UTC. They are disabled by
+    /// default, since hilog records its own timestamp for every entry.
+    pub fn format_timestamp(&mut self, timestamp: Option<TimestampPrecision>) -> &mut Self {
+        self.format.format_timestamp = timestamp;
+        self.format.format_timestamp_human = false;
+        self
+    }
+
+    /// Whether to write the time elapsed since the previous record in the default format.
+    ///
+    /// The delta is measured per thread and written as a header field after the
+    /// timestamp, e.g. `+12.3ms`. The first record on a thread shows `+0.0ms`.
+    /// This is a cheap way to spot latency spikes in a sequence of operations.
+    pub fn format_delta(&mut self, write: bool) -> &mut Self {
+        self.format.format_delta = write;
+        self
+    }
+
+    /// Whether to write the time elapsed since the logger was built in the default format.
+    ///
+    /// The uptime is written in seconds with millisecond resolution as a header
+    /// field after the timestamp, e.g. `12.345s`. It is formatted without heap
+    /// allocations.
+    pub fn format_uptime(&mut self, write: bool) -> &mut Self {
+        self.format.format_uptime = write;
+        self
+    }
+
+    /// Whether to write the date of timestamps only when the day changed.
+    ///
+    /// The first timestamp of each calendar day (in UTC) is written in full,
+    /// all others only with the time of day, e.g. `12:34:56Z`. This saves bytes
+    /// in long-running services that log continuously, while the date can still
+    /// be found in the log. Has no effect if timestamps are disabled.
+    pub fn format_timestamp_date_on_change(&mut self, on_change: bool) -> &mut Self {
+        self.format.format_timestamp_date_on_change = on_change;
+        self
+    }
+
+    /// Configures the timestamp to be written as weekday and time of day in UTC.
+    ///
+    /// This is a short form for people reading logs on the device, e.g.
+    /// `Mon 14:03:22`, instead of `2024-01-15T14:03:22Z`. Like all timestamps it
+    /// is in UTC, not local time: there is no time zone database to convert it.
+    /// Overridden by [`Builder::format_timestamp`] and the other precision presets.
+    pub fn format_timestamp_human_utc(&mut self) -> &mut Self {
+        self.format_timestamp(Some(TimestampPrecision::Seconds));
+        self.format.format_timestamp_human = true;
+        self
+    }
+
+    /// Configures the timestamp to use second precision.
+    pub fn format_timestamp_secs(&mut self) -> &mut Self {
+        self.format_timestamp(Some(TimestampPrecision::Seconds))
+    }
+
+    /// Configures the timestamp to use millisecond precision.
+    pub fn format_timestamp_millis(&mut self) -> &mut Self {
+        self.format_timestamp(Some(TimestampPrecision::Millis))
+    }
+
+    /// Configures the timestamp to use microsecond precision.
+    pub fn format_timestamp_micros(&mut self) -> &mut Self {
+        self.format_timestamp(Some(TimestampPrecision::Micros))
+    }
+
+    /// Configures the timestamp to use nanosecond precision.
+    pub fn format_timestamp_nanos(&mut self) -> &mut Self {
+        self.format_timestamp(Some(TimestampPrecision::Nanos))
+    }
+
+    /// Sets the clock used for timestamps in the default format.
+    ///
+    /// Defaults to [`SystemTime::now`]. This allows using a synchronized time
+    /// source, or a fixed clock to make the output deterministic.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::{Duration, UNIX_EPOCH};
+    /// use hilog::Builder;
+    ///
+    /// let mut builder = Builder::new();
+    ///
+    /// builder
+    ///     .format_timestamp_secs()
+    ///     .timestamp_source(|| UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    /// ```
+    pub fn timestamp_source<F>(&mut self, source: F) -> &mut Self
+    where
+        F: Fn() -> SystemTime + Sync + Send + 'static,
+    {
+        self.format.timestamp_source = Some(Box::new(source));
+        self
+    }
+
+    /// Configures the end of line suffix.
+    pub fn format_suffix(&mut self, suffix: &'static str) -> &mut Self {
+        self.format.format_suffix = suffix;
+        self
+    }
+
+    /// Whether to shrink the formatting buffer of a thread after each record.
+    ///
+    /// Records are formatted into a buffer per thread, which by default keeps
+    /// the capacity of the largest record ever formatted on that thread. When
+    /// enabled, the buffer is shrunk to 1 KiB after a larger record, trading a
+    /// reallocation for bounded memory use on devices with little RAM.
+    pub fn shrink_buffers(&mut self, shrink: bool) -> &mut Self {
+        self.shrink_buffers = shrink;
+        self
+    }
+
+    /// Sets the capacity the formatting buffer of a thread starts with.
+    ///
+    /// The buffer is reserved with this capacity when a thread logs for the
+    /// first time, which avoids growing it in steps if the typical size of a
+    /// record is known. With [`Builder::shrink_buffers`] the buffer is not shrunk
+    /// below this capacity. Defaults to 0, i.e. the buffer grows on demand.
+    pub fn buffer_capacity_hint(&mut self, capacity: usize) -> &mut Self {
+        self.buffer_capacity_hint = capacity;
+        self
+    }
+
+    /// Whether to emit a minimal fallback line when the format function fails.
+    ///
+    /// If the format function returns an error, whatever it wrote so far is
+    /// discarded and only the level and the message are printed instead.
+    /// Enabled by default. When disabled, the partially formatted output is
+    /// printed as is.
+    pub fn format_fallback(&mut self, fallback: bool) -> &mut Self {
+        self.format_fallback = fallback;
+        self
+    }
+
+    /// Sets a callback that is invoked with the formatted bytes of every record
+    /// right before they are passed to hilog.
+    ///
+    /// The callback may modify the bytes in place, e.g. to redact secrets.
+    ///
+    /// # Examples
+    ///
+    /// Mask all digits:
+    ///
+    /// ```
+    /// use hilog::Builder;
+    ///
+    /// let mut builder = Builder::new();
+    ///
+    /// builder.on_emit(|bytes| {
+    ///     for b in bytes.iter_mut().filter(|b| b.is_ascii_digit()) {
+    ///         *b = b'*';
+    ///     }
+    /// });
+    /// ```
+    pub fn on_emit<F>(&mut self, on_emit: F) -> &mut Self
+    where
+        F: Fn(&mut Vec<u8>) + Sync + Send + 'static,
+    {
+        self.writer.on_emit = Some(Box::new(on_emit));
+        self
+    }
+
+    /// Sends formatted messages to `sink` instead of hilog.
+    ///
+    /// Records are still filtered as usual, including the hilog loggability
+    /// check. See [`Sink`] for an example.
+    pub fn sink<S>(&mut self, sink: S) -> &mut Self
+    where
+        S: Sink + 'static,
+    {
+        self.writer.sink = Some(Box::new(sink));
+        self
+    }
+
+    /// Whether to replace invalid UTF-8 in messages before they are passed to hilog.
+    ///
+    /// A custom format or an `on_emit` callback can write arbitrary bytes, which
+    /// hilog may render garbled. When enabled, invalid sequences are replaced with
+    /// `U+FFFD REPLACEMENT CHARACTER`. Enabled by default; valid messages are not
+    /// copied.
+    pub fn validate_utf8(&mut self, validate: bool) -> &mut Self {
+        self.writer.sanitize.validate_utf8 = validate;
+        self
+    }
+
+    /// Sets how hard the writer tries to hand a message to hilog.
+    ///
+    /// With [`WriteMode::BestEffort`], the default, a message is dropped as soon
+    /// as `OH_LOG_Print` fails, which keeps the latency of logging low. With
+    /// [`WriteMode::Blocking`] failed calls are retried a few times before the
+    /// message is dropped. Either way, dropped messages are counted in
+    /// [`Logger::stats`].
+    pub fn write_mode(&mut self, mode: WriteMode) -> &mut Self {
+        self.writer.write_mode = mode;
+        self
+    }
+
+    /// Sets the maximum size of a formatted message in bytes.
+    ///
+    /// Formatting stops buffering once the limit is reached and the message is
+    /// printed truncated, at a character boundary, so that accidentally huge
+    /// records don't allocate megabytes on constrained devices. Defaults to 4096
+    /// bytes, the maximum hilog prints; `None` removes the limit.
+    ///
+    /// The JSON and CBOR formats only cut the message, so that their output stays
+    /// well-formed. They exceed the limit if everything but the message doesn't
+    /// fit, e.g. with large default key-value pairs.
+    pub fn max_formatted_len(&mut self, max_len: Option<usize>) -> &mut Self {
+        self.writer.max_formatted_len = max_len;
+        self
+    }
+
+    /// Configures what is written between the closing bracket of the header
+    /// and the message.
+    ///
+    /// Defaults to a single space. This is the only thing written after the
+    /// closing bracket, so with an empty separator the message directly follows
+    /// the header, e.g. `[INFO]message`. Nothing is written if there is no header.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hilog::Builder;
+    ///
+    /// let mut builder = Builder::new();
+    ///
+    /// // [INFO]message
+    /// builder.format_target(false).header_message_separator("");
+    /// ```
+    pub fn header_message_separator(&mut self, separator: &'static str) -> &mut Self {
+        self.format.format_header_separator = separator;
+        self
+    }
+
+    /// Sets a line that is logged once when the logger is installed.
+    ///
+    /// The banner is printed at info level with the fixed or default tag,
+    /// regardless of the filter directives, which makes it easy to tell which
+    /// build produced a log. It is only printed by [`Builder::init`] and its
+    /// variants, not by [`Builder::build`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hilog::Builder;
+    ///
+    /// let mut builder = Builder::new();
+    ///
+    /// builder.startup_banner(concat!("my_app ", env!("CARGO_PKG_VERSION")));
+    /// ```
+    pub fn startup_banner(&mut self, banner: &str) -> &mut Self {
+        self.startup_banner = Some(banner.to_owned());
+        self
+    }
+
+    /// Prints `alive` every `interval` from a background thread.
+    ///
+    /// The heartbeat helps to detect hung processes in the field. It is printed
+    /// with the domain of the logger, bypassing the filters and the format, and
+    /// starts when the logger is built. Use the returned handle to stop it.
+    ///
+    /// Intervals shorter than a second are raised to one second, so that the
+    /// heartbeat can't flood hilog.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `tag` can not be converted into a valid [`Tag`].
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::time::Duration;
+    /// use hilog::Builder;
+    /// use log::Level;
+    ///
+    /// let mut builder = Builder::new();
+    ///
+    /// let heartbeat = builder
+    ///     .heartbeat(Duration::from_secs(60), Level::Info, "heartbeat")
+    ///     .expect("valid tag");
+    /// builder.init();
+    ///
+    /// // On shutdown:
+    /// heartbeat.stop();
+    /// ```
+    pub fn heartbeat<T>(&mut self, interval: Duration, level: Level, tag: T) -> Result<HeartbeatHandle, T::Error>
+    where
+        T: TryInto<Tag>,
+    {
+        let state = Arc::default();
+        self.heartbeat = Some(Heartbeat {
+            interval: interval.max(heartbeat::MIN_INTERVAL),
+            level,
+            tag: tag.try_into()?,
+            state: Arc::clone(&state),
+        });
+        Ok(HeartbeatHandle { state })
+    }
+
+    /// Whether to set the minimum hilog level of the process when the logger is installed.
+    ///
+    /// The most verbose level enabled by the filters is passed, mapped with
+    /// [`Builder::level_map`], to `OH_LOG_SetMinLogLevel`, so that the native filter
+    /// of hilog agrees with the filters of this logger. If all levels are
+    /// disabled, the minimum is set to [`LogLevel::LOG_FATAL`].
+    #[cfg(feature = "api-15")]
+    pub fn sync_native_level(&mut self, sync: bool) -> &mut Self {
+        self.sync_native_level = sync;
+        self
+    }
+
+    /// Initializes the global logger with the built env logger.
+    ///
+    /// This should be called early in the execution of a Rust program. Any log
+    /// events that occur before initialization will be ignored.
+    ///
+    /// # Errors
+    ///
+    /// This function will fail if it is called more than once, or if another
+    /// library has already initialized a global logger.
+    pub fn try_init(&mut self) -> Result<(), SetLoggerError> {
+        self.try_init_and_report().map(|_| ())
+    }
+
+    /// Initializes the global logger and returns the maximum level it set.
+    ///
+    /// This is [`Builder::try_init`], but returns the level passed to
+    /// [`log::set_max_level`] on success, e.g. to log the effective verbosity.
+    ///
+    /// # Errors
+    ///
+    /// This function will fail if it is called more than once, or if another
+    /// library has already initialized a global logger.
+    pub fn try_init_and_report(&mut self) -> Result<LevelFilter, SetLoggerError> {
+        let banner = self.startup_banner.take();
+        #[cfg(feature = "api-15")]
+        let sync_native_level = self.sync_native_level;
+        // The heartbeat is only started once the logger is installed.
+        let heartbeat = self.heartbeat.take();
+        let logger = self.build();
+
+        let max_level = logger.filter();
+        #[cfg(feature = "api-15")]
+        let native_level = match max_level.to_level() {
+            Some(level) => logger.hilog_level(level),
+            None => LogLevel::LOG_FATAL,
+        };
+        let heartbeat = heartbeat.map(|heartbeat| {
+            let level = logger.hilog_level(heartbeat.level);
+            (heartbeat, level)
+        });
+        // Keep what the banner and the heartbeat need, the logger itself is
+        // dropped again if another one is already installed.
+        let writer = Arc::clone(&logger.writer);
+        let domain = logger.domain;
+        let tag = logger.tag.clone().unwrap_or_else(|| logger.default_tag.clone());
+        log::set_boxed_logger(Box::new(logger))?;
+        log::set_max_level(max_level);
+
+        #[cfg(feature = "api-15")]
+        if sync_native_level {
+            sys::set_min_log_level(native_level);
+        }
+
+        if let Some(banner) = banner {
+            Logger::print_banner(&writer, domain, &tag, &banner);
+        }
+        if let Some((heartbeat, level)) = heartbeat {
+            heartbeat.start(writer, domain, level);
+        }
+
+        Ok(max_level)
+    }
+
+    /// Initializes the global logger with the built env logger.
+    ///
+    /// This should be called early in the execution of a Rust program. Any log
+    /// events that occur before initialization will be ignored.
+    ///
+    /// # Panics
     ///
     /// This function will panic if it is called more than once, or if another
     /// library has already initialized a global logger.
@@ -266,57 +1800,1006 @@ impl Builder {
             .expect("Builder::init should not be called after logger initialized");
     }
 
-    /// Build an env logger.
+    /// Initializes the global logger and returns the maximum level it set.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if it is called more than once, or if another
+    /// library has already initialized a global logger.
+    pub fn init_and_report(&mut self) -> LevelFilter {
+        self.try_init_and_report()
+            .expect("Builder::init_and_report should not be called after logger initialized")
+    }
+
+    /// Builds only the filter, e.g. to share it with another logging backend.
+    ///
+    /// The filter contains all directives and the message filter added so far.
+    /// A logger built afterwards uses the same filter, so filters added after
+    /// this call have no effect.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hilog::Builder;
+    /// use log::LevelFilter;
+    ///
+    /// let mut builder = Builder::new();
+    /// builder.filter_level(LevelFilter::Info);
+    ///
+    /// let filter = builder.build_filter();
+    /// assert_eq!(filter.filter(), LevelFilter::Info);
+    /// ```
+    pub fn build_filter(&mut self) -> env_filter::Filter {
+        self.built_filter
+            .get_or_insert_with(|| self.filter.build())
+            .clone()
+    }
+
+    /// Uses a filter built elsewhere instead of the one configured on this builder.
+    ///
+    /// All filters added to this builder, before or after this call, are
+    /// ignored. Since the directives of a pre-built filter can't be inspected,
+    /// [`Logger::directives`] returns nothing for the built logger.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hilog::Builder;
+    ///
+    /// let filter = env_filter::Builder::new().parse("info,my_app::net=trace/timeout").build();
+    /// let mut builder = Builder::new();
+    ///
+    /// builder.with_filter(filter);
+    /// ```
+    pub fn with_filter(&mut self, filter: env_filter::Filter) -> &mut Self {
+        self.built_filter = Some(filter);
+        self.prebuilt_filter = true;
+        self
+    }
+
+    /// Build an env logger.
+    ///
+    /// The returned logger implements the `Log` trait and can be installed manually
+    /// or nested within another logger. Building does not touch any global state,
+    /// in particular neither the global logger nor [`log::set_max_level`].
+    pub fn build(&mut self) -> Logger {
+        assert!(!self.built, "attempt to re-use consumed builder");
+        self.built = true;
+
+        // The format needs the level map to write numeric levels.
+        self.format.level_map = self.level_map.clone();
+
+        // Takes the timestamp source out of the format builder before it is consumed.
+        let context_format = self.format.custom_context_format.take().map(|format| ContextFormat {
+            format,
+            timestamp_source: self
+                .format
+                .timestamp_source
+                .take()
+                .unwrap_or_else(|| Box::new(SystemTime::now)),
+        });
+
+        let writer = Arc::new(self.writer.build());
+        if let Some(heartbeat) = self.heartbeat.take() {
+            let level = match &self.level_map {
+                Some(level_map) => level_map(heartbeat.level),
+                None => heartbeat.level.into(),
+            };
+            heartbeat.start(Arc::clone(&writer), self.log_domain, level);
+        }
+
+        Logger {
+            domain: self.log_domain,
+            filter: self.built_filter.take().unwrap_or_else(|| self.filter.build()),
+            overrides: Arc::new(LevelOverrides::new()),
+            directives: if self.prebuilt_filter {
+                Vec::new()
+            } else if self.directives.is_empty() {
+                // Mirrors the default directive `env_filter` adds if none exist.
+                vec![(None, LevelFilter::Error)]
+            } else {
+                mem::take(&mut self.directives)
+            },
+            writer,
+            format: self.format.build(),
+            context_format,
+            format_fallback: self.format_fallback,
+            level_formats: mem::take(&mut self.level_formats),
+            dual_format: self.dual_format.take(),
+            dual_seq: AtomicU64::new(0),
+            tag: self.tag.take(),
+            default_tag: self.default_tag.clone(),
+            level_map: self.level_map.take(),
+            log_type_fn: self.log_type_fn.take(),
+            loggability: LoggabilityCache::new(self.loggability_cache_ttl),
+            domain_levels: mem::take(&mut self.domain_levels),
+            normalize_tags: self.normalize_tags,
+            use_message_filters: self.use_message_filters,
+            tag_from_target: self.tag_from_target,
+            tag_map: mem::take(&mut self.tag_map),
+            domain_routes: mem::take(&mut self.domain_routes),
+            always_allow_level: self.always_allow_level,
+            rate_limit: self
+                .global_rate_limit
+                .map(|(max_records, per)| RateLimit::new(max_records, per)),
+            rate_limit_only_below: self.rate_limit_only_below,
+            mirror_domains: mem::take(&mut self.mirror_domains),
+            mirror_when: self.mirror_when.take(),
+            gate: self.gate.take(),
+            #[cfg(feature = "backtrace")]
+            backtrace_on_error: self.backtrace_on_error,
+            shrink_buffers: self.shrink_buffers,
+            buffer_capacity_hint: self.buffer_capacity_hint,
+            internal_tag: self.internal_tag.clone(),
+            internal_domain: self.internal_domain.unwrap_or(self.log_domain),
+            session: AtomicU64::new(0),
+        }
+    }
+
+}
+
+pub use crate::ohfmt::{HilogContextFormatFn, HilogFormatFn};
+pub use crate::ohfmt::writer::{sanitize_message, HilogWriter, SanitizeOptions, WriteMode};
+
+/// The hilog logger.
+///
+/// A `Logger` is created with [`Builder::build`] and is fully standalone: it only
+/// touches global state if it is installed via [`Builder::init`] or
+/// [`Builder::try_init`]. This makes it possible to drive it manually, e.g. when
+/// composing several `Log` implementations.
+///
+/// # Examples
+///
+/// ```
+/// use hilog::Builder;
+/// use log::{Level, LevelFilter, Log, Record};
+///
+/// let logger = Builder::new().filter_level(LevelFilter::Info).build();
+///
+/// let record = Record::builder()
+///     .level(Level::Info)
+///     .target("my_app")
+///     .args(format_args!("hello from a standalone logger"))
+///     .build();
+///
+/// if logger.enabled(record.metadata()) {
+///     logger.log(&record);
+/// }
+/// logger.flush();
+/// ```
+pub struct Logger  {
+    domain: LogDomain,
+    filter: env_filter::Filter,
+    overrides: Arc<LevelOverrides>,
+    directives: Vec<(Option<String>, LevelFilter)>,
+    writer: Arc<HilogWriter>,
+    format: HilogFormatFn,
+    context_format: Option<ContextFormat>,
+    format_fallback: bool,
+    // Indexed by `Level as usize - 1`.
+    level_formats: [Option<HilogFormatFn>; 5],
+    dual_format: Option<HilogFormatFn>,
+    tag: Option<Tag>,
+    default_tag: Tag,
+    level_map: Option<LevelMapFn>,
+    log_type_fn: Option<LogTypeFn>,
+    loggability: LoggabilityCache,
+    domain_levels: Vec<(LogDomain, LevelFilter)>,
+    normalize_tags: bool,
+    use_message_filters: bool,
+    tag_from_target: bool,
+    tag_map: Vec<(String, Tag)>,
+    domain_routes: Vec<(String, LogDomain)>,
+    always_allow_level: Option<Level>,
+    rate_limit: Option<RateLimit>,
+    rate_limit_only_below: Option<Level>,
+    mirror_domains: Vec<LogDomain>,
+    mirror_when: Option<MirrorWhenFn>,
+    gate: Option<GateFn>,
+    #[cfg(feature = "backtrace")]
+    backtrace_on_error: bool,
+    shrink_buffers: bool,
+    buffer_capacity_hint: usize,
+    internal_tag: Tag,
+    internal_domain: LogDomain,
+    // The id of the current session, see `Logger::mark_session_start`.
+    session: AtomicU64,
+    // The sequence id shared by both lines of a record, see `Builder::dual_format`.
+    dual_seq: AtomicU64,
+}
+
+/// Checks a filter string in the form of `RUST_LOG` without applying it.
+///
+/// Reports unknown levels, malformed directives and invalid regular expressions,
+/// which [`Builder::parse_filters`] would only print to stderr and ignore.
+///
+/// # Errors
+///
+/// Fails if `filters` contains an invalid directive or message filter.
+///
+/// # Examples
+///
+/// ```
+/// assert!(hilog::validate_filter("info,my_app::net=trace").is_ok());
+/// assert!(hilog::validate_filter("my_app=loud").is_err());
+/// ```
+pub fn validate_filter(filters: &str) -> Result<(), FilterParseError> {
+    env_filter::Builder::new().try_parse(filters).map(|_| ())
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Globally enables or disables all hilog loggers at runtime.
+///
+/// While disabled, every record is dropped right away, before any filtering,
+/// including fatal ones. This is a single atomic load per record and takes
+/// effect immediately, without changing any filters. Logging is enabled by
+/// default.
+///
+/// # Examples
+///
+/// ```
+/// hilog::set_enabled(false);
+/// log::error!("not printed");
+/// hilog::set_enabled(true);
+/// ```
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Flushes the globally installed logger.
+///
+/// This gives embedders that control the lifetime of the process a single
+/// point to make sure all records are written, e.g. right before calling a
+/// native shutdown routine, without holding a reference to the [`Logger`].
+/// hilog itself writes synchronously, so this only has an effect on a
+/// [`Sink`] that buffers. If no logger is installed this does nothing.
+///
+/// # Examples
+///
+/// ```
+/// log::info!("shutting down");
+/// hilog::flush_all();
+/// ```
+pub fn flush_all() {
+    log::logger().flush();
+}
+
+/// Formatter for records logged after the thread-local one was destroyed.
+static SHUTDOWN_FORMATTER: Mutex<Option<HilogFormatter>> = Mutex::new(None);
+
+thread_local! {
+    static FORMATTER: RefCell<Option<HilogFormatter>> = const { RefCell::new(None) };
+}
+
+thread_local! {
+    static IN_LOG: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Marks the current thread as printing a record until dropped.
+struct ReentrancyGuard;
+
+impl ReentrancyGuard {
+    /// Returns `None` if the current thread is already printing a record.
+    fn enter() -> Option<ReentrancyGuard> {
+        // Without thread-local storage we can't detect re-entrancy, so allow it.
+        let entered = IN_LOG.try_with(|in_log| !in_log.replace(true)).unwrap_or(true);
+        entered.then_some(ReentrancyGuard)
+    }
+}
+
+impl Drop for ReentrancyGuard {
+    fn drop(&mut self) {
+        let _ = IN_LOG.try_with(|in_log| in_log.set(false));
+    }
+}
+
+/// Drops the formatter cached for the current thread.
+///
+/// Records are formatted into a thread-local buffer, which keeps the capacity of
+/// the largest record formatted on that thread. After calling this, the next
+/// record logged on this thread starts with a fresh buffer. This is mainly
+/// useful for benchmarks and tests.
+pub fn reset_thread_formatter() {
+    let _ = FORMATTER.try_with(|tl_buf| {
+        if let Ok(mut tl_buf) = tl_buf.try_borrow_mut() {
+            *tl_buf = None;
+        }
+    });
+}
+
+/// Extracts the directives of a filter string, as `env_filter` parses them.
+///
+/// Invalid directives are skipped, `env_filter` already reports them.
+fn parse_directives(spec: &str) -> Vec<(Option<String>, LevelFilter)> {
+    let mut parts = spec.split('/');
+    let mods = parts.next().unwrap_or_default();
+    if parts.nth(1).is_some() {
+        // Too many '/'s, `env_filter` ignores the whole spec.
+        return Vec::new();
+    }
+
+    mods.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| {
+            let mut parts = s.split('=');
+            match (parts.next(), parts.next().map(str::trim), parts.next()) {
+                (Some(part0), None, None) => Some(match part0.parse() {
+                    Ok(level) => (None, level),
+                    Err(_) => (Some(part0.to_owned()), LevelFilter::max()),
+                }),
+                (Some(part0), Some(""), None) => Some((Some(part0.to_owned()), LevelFilter::max())),
+                (Some(part0), Some(part1), None) => part1.parse().ok().map(|level| (Some(part0.to_owned()), level)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Removes a `#` comment from a line of a filter file.
+///
+/// A `#` after the first `/` is part of the message filter, not a comment.
+fn strip_filter_comment(line: &str) -> &str {
+    match line.find(['#', '/']) {
+        Some(i) if line.as_bytes()[i] == b'#' => line[..i].trim(),
+        _ => line.trim(),
+    }
+}
+
+/// Splits a `domain:NNNN::` prefix off `target`, after an optional `private::` marker.
+///
+/// The domain is decimal or hexadecimal with a `0x` prefix. Returns `None` if
+/// there is no such prefix or the domain is malformed.
+fn split_target_domain(target: &str) -> Option<(LogDomain, &str)> {
+    let target = target.strip_prefix(PRIVATE_TARGET_PREFIX).unwrap_or(target);
+    let rest = target.strip_prefix(DOMAIN_TARGET_PREFIX)?;
+    let (domain, rest) = rest.split_once("::").unwrap_or((rest, ""));
+    let domain = match domain.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok()?,
+        None => domain.parse().ok()?,
+    };
+    Some((LogDomain::new(domain), rest))
+}
+
+/// Removes the `private::` and `domain:NNNN::` markers from a target.
+fn strip_target_markers(target: &str) -> &str {
+    match split_target_domain(target) {
+        Some((_, rest)) => rest,
+        None => target.strip_prefix(PRIVATE_TARGET_PREFIX).unwrap_or(target),
+    }
+}
+
+/// Appends the backtrace of the caller on new lines, if capturing is enabled.
+#[cfg(feature = "backtrace")]
+fn write_backtrace(formatter: &mut HilogFormatter) -> io::Result<()> {
+    let backtrace = std::backtrace::Backtrace::capture();
+    if backtrace.status() != std::backtrace::BacktraceStatus::Captured {
+        return Ok(());
+    }
+    if !formatter.as_bytes().ends_with(b"\n") {
+        formatter.write_all(b"\n")?;
+    }
+    write!(formatter, "stack backtrace:\n{}", backtrace)
+}
+
+/// Returns the value of the longest prefix matching `path`, with `::` boundaries.
+fn longest_prefix_match<'a, T>(entries: &'a [(String, T)], path: &str) -> Option<&'a T> {
+    entries
+        .iter()
+        .filter(|(prefix, _)| {
+            path.strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+        })
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, value)| value)
+}
+
+/// Removes synthetic path segments such as `{{closure}}` from a tag.
+fn normalize_tag(tag: &str) -> Cow<'_, str> {
+    if !tag.contains("{{") {
+        return Cow::Borrowed(tag);
+    }
+
+    let segments: Vec<&str> = tag
+        .split("::")
+        .filter(|segment| !(segment.starts_with("{{") && segment.ends_with("}}")))
+        .collect();
+    Cow::Owned(segments.join("::"))
+}
+
+// `log::set_boxed_logger` requires the logger to be `Send + Sync`.
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Logger>();
+};
+
+impl Logger {
+    /// Creates a logger from its components.
+    ///
+    /// All other settings have the same defaults as with [`Builder`]. Since the
+    /// directives of a pre-built filter can't be inspected, [`Logger::directives`]
+    /// returns nothing for a logger created this way.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::io::Write;
+    /// use hilog::{HilogWriter, LogDomain, Logger};
+    ///
+    /// let filter = env_filter::Builder::new().parse("info").build();
+    /// let logger = Logger::new(
+    ///     LogDomain::new(0x1234),
+    ///     filter,
+    ///     HilogWriter::default(),
+    ///     Box::new(|buf, record| write!(buf, "{}", record.args())),
+    /// );
+    /// ```
+    pub fn new(domain: LogDomain, filter: env_filter::Filter, writer: HilogWriter, format: HilogFormatFn) -> Logger {
+        Logger {
+            domain,
+            filter,
+            directives: Vec::new(),
+            writer: Arc::new(writer),
+            format,
+            internal_domain: domain,
+            ..Builder::new().build()
+        }
+    }
+
+    /// Returns the maximum `LevelFilter` that this env logger instance is
+    /// configured to output.
+    pub fn filter(&self) -> LevelFilter {
+        let always = self.always_allow_level.map_or(LevelFilter::Off, |level| level.to_level_filter());
+        self.filter.filter().max(always)
+    }
+
+    /// Returns the filter directives this logger applies.
+    ///
+    /// Each directive is a module name (or `None` for all modules) together with
+    /// the maximum level enabled for it, in the order they were added to the
+    /// [`Builder`].
+    pub fn directives(&self) -> impl Iterator<Item = (Option<String>, LevelFilter)> + '_ {
+        self.directives.iter().cloned()
+    }
+
+    /// Returns a handle to adjust this logger after it has been built or installed.
+    pub fn handle(&self) -> Handle {
+        Handle {
+            overrides: Arc::clone(&self.overrides),
+        }
+    }
+
+    /// Returns a snapshot of the counters of this logger.
+    ///
+    /// This allows to surface the health of logging in application metrics, e.g.
+    /// to detect that records are being dropped.
+    pub fn stats(&self) -> LoggerStats {
+        self.writer.counters().snapshot()
+    }
+
+    /// Returns the number of filtered records per level, from [`Level::Error`] to [`Level::Trace`].
+    ///
+    /// These are the records counted in [`LoggerStats::records_filtered`], which
+    /// tells how many records raising a level would add. Records above
+    /// [`log::max_level`] are rejected by the `log` macros before they reach the
+    /// logger and are not counted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hilog::Builder;
+    ///
+    /// let logger = Builder::new().build();
+    ///
+    /// let [_error, _warn, _info, debug, _trace] = logger.filtered_counts();
+    /// println!("filtered {} debug records", debug);
+    /// ```
+    pub fn filtered_counts(&self) -> [u64; 5] {
+        self.writer.counters().filtered_by_level()
+    }
+
+    /// Returns the domain a record is logged to.
+    fn domain(&self, record: &Record<'_>) -> LogDomain {
+        if let Some((domain, _)) = split_target_domain(record.target()) {
+            return domain;
+        }
+        if self.domain_routes.is_empty() {
+            return self.domain;
+        }
+
+        let target = strip_target_markers(record.target());
+        longest_prefix_match(&self.domain_routes, target)
+            .or_else(|| record.module_path().and_then(|path| longest_prefix_match(&self.domain_routes, path)))
+            .copied()
+            .unwrap_or(self.domain)
+    }
+
+    /// Returns the log type a record is logged with.
+    fn log_type(&self, record: &Record<'_>) -> LogType {
+        match &self.log_type_fn {
+            Some(log_type_fn) => log_type_fn(record),
+            None => self.writer.log_type(),
+        }
+    }
+
+    /// Whether the gate set with [`Builder::gate`] lets records through.
+    fn gate_open(&self) -> bool {
+        self.gate.as_ref().map_or(true, |gate| gate())
+    }
+
+    /// Whether records of `level` bypass all filters.
+    fn always_allowed(&self, level: Level) -> bool {
+        self.always_allow_level.is_some_and(|always| level <= always)
+    }
+
+    /// Whether `level` is allowed by the level configured for `domain`, if any.
+    fn domain_allows(&self, domain: LogDomain, level: Level) -> bool {
+        if self.always_allowed(level) {
+            return true;
+        }
+        self.domain_levels
+            .iter()
+            .find(|(d, _)| *d == domain)
+            .map_or(true, |(_, max)| level <= *max)
+    }
+
+    /// Derives the hilog tag of a record.
+    ///
+    /// Uses the fixed tag if set, otherwise the module path, then the target, and
+    /// finally the default tag.
+    fn tag<'a>(&'a self, record: &Record<'_>) -> Cow<'a, CStr> {
+        if let Some(tag) = &self.tag {
+            return Cow::Borrowed(tag.as_c_str());
+        }
+
+        let module_path = record.module_path().filter(|path| !path.is_empty());
+        let target = Some(strip_target_markers(record.target())).filter(|target| !target.is_empty());
+        if let Some(tag) = target.and_then(|target| self.mapped_tag(target)) {
+            return Cow::Borrowed(tag.as_c_str());
+        }
+        if let Some(tag) = module_path.and_then(|path| self.mapped_tag(path)) {
+            return Cow::Borrowed(tag.as_c_str());
+        }
+
+        let derived = if self.tag_from_target {
+            target.or(module_path)
+        } else {
+            module_path.or(target)
+        };
+
+        derived
+            .map(|tag| if self.normalize_tags { normalize_tag(tag) } else { Cow::Borrowed(tag) })
+            .filter(|tag| !tag.is_empty())
+            .and_then(|tag| CString::new(tag.into_owned()).ok())
+            .map(Cow::Owned)
+            .unwrap_or(Cow::Borrowed(self.default_tag.as_c_str()))
+    }
+
+    /// Returns the tag of the longest prefix in the tag map matching `path`.
+    fn mapped_tag(&self, path: &str) -> Option<&Tag> {
+        longest_prefix_match(&self.tag_map, path)
+    }
+
+    /// Maps a `log` level to the hilog level it is printed with.
+    fn hilog_level(&self, level: Level) -> LogLevel {
+        match &self.level_map {
+            Some(level_map) => level_map(level),
+            None => level.into(),
+        }
+    }
+
+    /// Logs an already formatted C string, bypassing the format.
     ///
-    /// The returned logger implements the `Log` trait and can be installed manually
-    /// or nested within another logger.
-    pub fn build(&mut self) -> Logger {
-        assert!(!self.built, "attempt to re-use consumed builder");
-        self.built = true;
+    /// The message is subject to the same filtering as regular records, including
+    /// the message filter, using `tag` as the target, followed by the loggability
+    /// check of hilog, the rate limit and the mirroring to other domains. Like a
+    /// formatted record, it is truncated to [`Builder::max_formatted_len`]. This
+    /// avoids formatting and copying the message when it already is a C string,
+    /// e.g. when it comes from C code: valid UTF-8 is checked once and the message
+    /// is only copied if it is invalid or has to be truncated. `tag` is used as
+    /// is: the `private::` and `domain:NNNN::` markers, the tag map and the routes
+    /// don't apply, and the record is printed to the domain of the logger as a
+    /// public argument.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hilog::Builder;
+    /// use log::Level;
+    ///
+    /// let logger = Builder::new().build();
+    ///
+    /// logger.log_cstr(Level::Error, c"ffi", c"message from C");
+    /// ```
+    pub fn log_cstr(&self, level: Level, tag: &CStr, msg: &CStr) {
+        // The filters and callbacks get a record borrowing the message, and the
+        // writer relies on it being valid UTF-8.
+        let lossy;
+        let (text, msg) = match msg.to_str() {
+            Ok(text) => (text, msg),
+            Err(_) => {
+                let text = msg.to_string_lossy().into_owned();
+                // SAFETY: `msg` contains no NUL bytes and replacing invalid UTF-8 adds none.
+                lossy = unsafe { CString::from_vec_unchecked(text.into_bytes()) };
+                (lossy.to_str().unwrap_or_default(), lossy.as_c_str())
+            }
+        };
+        self.log_cstr_record(
+            &Record::builder()
+                .level(level)
+                .target(tag.to_str().unwrap_or_default())
+                .args(format_args!("{}", text))
+                .build(),
+            tag,
+            msg,
+        );
+    }
 
-        Logger {
-            domain: self.log_domain,
-            filter: self.filter.build(),
-            writer: self.writer.build(),
-            format: self.format.build(),
+    /// Prints `msg`, which must be valid UTF-8, for `record`.
+    fn log_cstr_record(&self, record: &Record<'_>, tag: &CStr, msg: &CStr) {
+        // Like `Log::log`, the filters and callbacks may log themselves.
+        let Some(_guard) = ReentrancyGuard::enter() else {
+            self.writer.counters().dropped();
+            self.report_reentrant_log();
+            return;
+        };
+
+        if !ENABLED.load(Ordering::Relaxed) || !self.gate_open() {
+            return;
+        }
+        let level = record.level();
+        if !self.matches(record) || !self.domain_allows(self.domain, level) {
+            self.writer.counters().filtered(level);
+            return;
+        }
+
+        let native = self.hilog_level(level);
+        if !self.is_loggable(self.domain, tag, native) {
+            self.writer.counters().filtered(level);
+            return;
+        }
+        if !self.within_rate_limit(level) {
+            self.writer.counters().dropped();
+            return;
+        }
+
+        let route = Route {
+            domain: self.domain,
+            tag: Cow::Borrowed(tag),
+            level: native,
+            log_type: self.log_type(record),
+            private: false,
+        };
+        let res = self.writer.print_cstr_as(msg, true, route.log_type, native, route.domain, tag);
+        self.writer.counters().record(res);
+        for domain in self.mirrors(record, &route) {
+            let _ = self.writer.print_cstr_as(msg, true, route.log_type, native, domain, tag);
         }
     }
 
-}
+    /// Prints a marker for the start of a session, e.g. when the app comes to the foreground.
+    ///
+    /// The marker is printed at info level with the tag `hilog-session`,
+    /// bypassing the filters and the format, as
+    /// `HILOG_SESSION_START id=<id> ts=<milliseconds since the unix epoch>`, so
+    /// that tools can segment exported logs by session. Session ids count up from
+    /// 1 for every logger. Returns the id of the new session.
+    pub fn mark_session_start(&self) -> u64 {
+        let id = self.session.fetch_add(1, Ordering::Relaxed) + 1;
+        let ts = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        self.print_session_marker(format!("HILOG_SESSION_START id={id} ts={ts}"));
+        id
+    }
 
-use crate::ohfmt::HilogFormatFn;
-use crate::ohfmt::writer::HilogWriter;
+    /// Prints a marker for the end of the current session.
+    ///
+    /// Printed like [`Logger::mark_session_start`], as
+    /// `HILOG_SESSION_END id=<id> reason=<reason>`. Newlines in `reason` are
+    /// replaced by spaces to keep the marker on a single line.
+    pub fn mark_session_end(&self, reason: &str) {
+        let id = self.session.load(Ordering::Relaxed);
+        let reason = reason.replace(['\n', '\r'], " ");
+        self.print_session_marker(format!("HILOG_SESSION_END id={id} reason={reason}"));
+    }
 
-pub struct Logger  {
-    domain: LogDomain,
-    filter: env_filter::Filter,
-    writer: HilogWriter,
-    format: HilogFormatFn,
+    fn print_session_marker(&self, marker: String) {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+        let Ok(marker) = CString::new(marker.replace('\0', "")) else {
+            return;
+        };
+        let _ = self.writer.print_cstr(&marker, LogLevel::LOG_INFO, self.domain, SESSION_TAG);
+    }
+
+    /// Prints the startup banner, bypassing filters and format.
+    fn print_banner(writer: &HilogWriter, domain: LogDomain, tag: &Tag, banner: &str) {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+        let Ok(banner) = CString::new(banner.replace('\0', "")) else {
+            return;
+        };
+        let _ = writer.print_cstr(&banner, LogLevel::LOG_INFO, domain, tag.as_c_str());
+    }
+
+    /// Emits a one-time diagnostic about a dropped re-entrant log call.
+    fn report_reentrant_log(&self) {
+        static REPORTED: AtomicBool = AtomicBool::new(false);
+
+        if !REPORTED.swap(true, Ordering::Relaxed) {
+            self.print_internal(
+                LogLevel::LOG_WARN,
+                c"dropped a log call made while formatting or printing another record; further occurrences are not reported",
+            );
+        }
+    }
+
+    /// Prints a diagnostic of the logger itself, with the internal tag and domain.
+    fn print_internal(&self, level: LogLevel, msg: &CStr) {
+        let _ = self.writer.print_cstr(msg, level, self.internal_domain, self.internal_tag.as_c_str());
+    }
+
+    /// Takes a record of `level` from the global rate limit, if any.
+    ///
+    /// Reports the records dropped in the previous window once a new one starts.
+    fn within_rate_limit(&self, level: Level) -> bool {
+        let Some(rate_limit) = &self.rate_limit else {
+            return true;
+        };
+        if self.rate_limit_only_below.is_some_and(|below| level <= below) {
+            return true;
+        }
+
+        let admission = rate_limit.acquire();
+        if admission.dropped_before > 0 {
+            let msg = format!("rate limit exceeded, dropped {} records", admission.dropped_before);
+            if let Ok(msg) = CString::new(msg) {
+                self.print_internal(LogLevel::LOG_WARN, &msg);
+            }
+        }
+        admission.allowed
+    }
+
+    /// Discards the cached results of the hilog loggability check.
+    ///
+    /// Useful after changing the hilog log level of the process, e.g. with the
+    /// `hilog` command line tool, to have the change take effect immediately.
+    pub fn clear_loggability_cache(&self) {
+        self.loggability.clear();
+    }
+
+    fn is_loggable(&self, domain: LogDomain, tag: &CStr, level: LogLevel) -> bool {
+        #[cfg(feature = "test-util")]
+        if sink::test_sink().is_some() {
+            return true;
+        }
+        self.loggability.get_or_query(domain, tag, level, || {
+            sys::is_loggable(domain.0.into(), tag, level)
+        })
+    }
+}
+
+/// Where and how a record that passed all filters is printed.
+pub(crate) struct Route<'a> {
+    pub(crate) domain: LogDomain,
+    pub(crate) tag: Cow<'a, CStr>,
+    pub(crate) level: LogLevel,
+    pub(crate) log_type: LogType,
+    // Whether the target carries the private marker.
+    pub(crate) private: bool,
 }
 
 impl Logger {
-    /// Returns the maximum `LevelFilter` that this env logger instance is
-    /// configured to output.
-    pub fn filter(&self) -> LevelFilter {
-        self.filter.filter()
+    /// Applies all filters to `record` and returns how it is printed, if at all.
+    pub(crate) fn route(&self, record: &Record<'_>) -> Option<Route<'_>> {
+        if !ENABLED.load(Ordering::Relaxed) || !self.gate_open() {
+            return None;
+        }
+
+        if !self.matches(record) {
+            self.writer.counters().filtered(record.level());
+            return None;
+        }
+
+        let domain = self.domain(record);
+        if !self.domain_allows(domain, record.level()) {
+            self.writer.counters().filtered(record.level());
+            return None;
+        }
+
+        let tag = self.tag(record);
+        let level = self.hilog_level(record.level());
+        if !self.is_loggable(domain, &tag, level) {
+            self.writer.counters().filtered(record.level());
+            return None;
+        }
+        if !self.within_rate_limit(record.level()) {
+            self.writer.counters().dropped();
+            return None;
+        }
+
+        Some(Route {
+            domain,
+            tag,
+            level,
+            log_type: self.log_type(record),
+            private: record.target().starts_with(PRIVATE_TARGET_PREFIX),
+        })
     }
 
-    fn is_loggable(&self, tag: &CStr, level: LogLevel) -> bool {
-        unsafe {
-            OH_LOG_IsLoggable(self.domain.0.into(), tag.as_ptr(), level)
+    /// Whether the level filters, and the message filter if enabled, let `record` through.
+    fn matches(&self, record: &Record<'_>) -> bool {
+        self.always_allowed(record.level())
+            || match self.overrides.level_for(record.metadata()) {
+                Some(level) => record.level() <= level,
+                // Unlike `enabled`, `matches` also applies the message filter.
+                None if self.use_message_filters => self.filter.matches(record),
+                None => self.filter.enabled(record.metadata()),
+            }
+    }
+
+    /// Returns the domains `record` is mirrored to, besides its own domain.
+    pub(crate) fn mirrors<'a>(&'a self, record: &Record<'_>, route: &'a Route<'_>) -> impl Iterator<Item = LogDomain> + 'a {
+        let mirrored = !self.mirror_domains.is_empty()
+            && self.mirror_when.as_ref().map_or(true, |mirror_when| mirror_when(record));
+        let level = record.level();
+
+        self.mirror_domains
+            .iter()
+            .copied()
+            .filter(move |_| mirrored)
+            .filter(move |&domain| domain != route.domain)
+            .filter(move |&domain| self.domain_allows(domain, level))
+            .filter(move |&domain| self.is_loggable(domain, &route.tag, route.level))
+    }
+
+    /// Formats `record` with the configured format, applying the fallback on errors.
+    pub(crate) fn format_record(&self, formatter: &mut HilogFormatter, record: &Record<'_>, route: &Route<'_>) -> io::Result<()> {
+        // Anything written before, like the sequence id of `dual_format`, is kept.
+        let start = formatter.len();
+        let res = match (&self.level_formats[record.level() as usize - 1], &self.context_format) {
+            (Some(level_format), _) => level_format(formatter, record),
+            (None, Some(context_format)) => context_format.write(formatter, record, route.domain, &route.tag),
+            (None, None) => (self.format)(formatter, record),
+        };
+        let res = if res.is_err() && self.format_fallback {
+            // Don't print a half-formatted line, fall back to just the message.
+            formatter.truncate(start);
+            write!(formatter, "{} {}", record.level(), record.args())
+        } else {
+            res
+        };
+
+        #[cfg(feature = "backtrace")]
+        if res.is_ok() && self.backtrace_on_error && record.level() == Level::Error {
+            return write_backtrace(formatter);
         }
+        res
+    }
+
+    /// Filters, formats and prints `record`, exactly as the [`Log`] implementation.
+    ///
+    /// This allows another logger to delegate selected records to this one,
+    /// without installing it as the global logger.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hilog::Builder;
+    /// use log::{Level, Record};
+    ///
+    /// let logger = Builder::new().build();
+    ///
+    /// logger.forward(
+    ///     &Record::builder()
+    ///         .level(Level::Info)
+    ///         .args(format_args!("forwarded"))
+    ///         .build(),
+    /// );
+    /// ```
+    pub fn forward(&self, record: &Record<'_>) {
+        Log::log(self, record);
+    }
+
+    /// Logs `record` as if it happened at `timestamp`.
+    ///
+    /// The formats use `timestamp` instead of asking [`Builder::timestamp_source`],
+    /// e.g. to replay recorded events or to log events timestamped by another
+    /// source. This applies to the timestamp of the default format, the JSON and
+    /// CBOR formats and [`FormatContext::timestamp`]; hilog still records the
+    /// time the entry is printed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::{Duration, UNIX_EPOCH};
+    /// use hilog::Builder;
+    /// use log::{Level, Record};
+    ///
+    /// let logger = Builder::new().format_timestamp_millis().build();
+    ///
+    /// logger.log_at(
+    ///     &Record::builder()
+    ///         .level(Level::Info)
+    ///         .args(format_args!("replayed"))
+    ///         .build(),
+    ///     UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+    /// );
+    /// ```
+    pub fn log_at(&self, record: &Record<'_>, timestamp: SystemTime) {
+        ohfmt::timestamp::with_override(timestamp, || Log::log(self, record));
+    }
+
+    /// Logs several records as one contiguous block.
+    ///
+    /// Records logged to the [`LogGroup`] are filtered and formatted as usual,
+    /// but only printed when `f` returns. They are then printed back to back,
+    /// without records of other threads in between. Records logged with the
+    /// regular logging macros while `f` runs are printed immediately.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hilog::Builder;
+    /// use log::{Level, Record};
+    ///
+    /// let logger = Builder::new().build();
+    ///
+    /// logger.group(|group| {
+    ///     for line in ["report:", "  a = 1", "  b = 2"] {
+    ///         group.log(
+    ///             &Record::builder()
+    ///                 .level(Level::Error)
+    ///                 .target("my_app")
+    ///                 .args(format_args!("{}", line))
+    ///                 .build(),
+    ///         );
+    ///     }
+    /// });
+    /// ```
+    pub fn group<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut LogGroup<'_>) -> R,
+    {
+        let mut group = LogGroup::new(self);
+        let res = f(&mut group);
+        group.flush();
+        res
     }
 }
 
 impl Log for Logger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        self.filter.enabled(metadata)
+        if !ENABLED.load(Ordering::Relaxed) || !self.gate_open() {
+            return false;
+        }
+        if self.always_allowed(metadata.level()) {
+            return true;
+        }
+        match self.overrides.level_for(metadata) {
+            Some(level) => metadata.level() <= level,
+            None => self.filter.enabled(metadata),
+        }
     }
 
     fn log(&self, record: &Record) {
-        if ! self.enabled(record.metadata()) {
+        // Logging from within the filters, the format function or anything else
+        // called while routing or printing would recurse into `log`, so such records
+        // are dropped.
+        let Some(_guard) = ReentrancyGuard::enter() else {
+            self.writer.counters().dropped();
+            self.report_reentrant_log();
             return;
-        }
+        };
+
+        let Some(route) = self.route(record) else {
+            return;
+        };
 
         // Todo: we could write to a fixed size array on the stack, since hilog anyway has a
         // maximum supported size for tag and log.
@@ -327,22 +2810,44 @@ impl Log for Logger {
         // so will always at least have capacity for the largest log record formatted
         // on that thread.
         //
-        // If multiple `Logger`s are used by the same threads then the thread-local
-        // formatter might have different color support. If this is the case the
-        // formatter and its buffer are discarded and recreated.
+        // The thread-local formatter only holds the buffer and its size limit,
+        // which is reset for every record, so it can safely be shared by multiple
+        // `Logger`s used on the same thread.
 
-        thread_local! {
-                static FORMATTER: RefCell<Option<HilogFormatter>> = const { RefCell::new(None) };
-            }
-        
-        let print = |formatter: &mut HilogFormatter, record: &Record<'_>| {
-            let tag = record.module_path().and_then(|path| CString::new(path).ok())
-                .unwrap_or_default();
-            let _ =
-                (self.format)(formatter, record).and_then(|_| formatter.print(&self.writer, record.level().into(), self.domain, tag.as_ref()));
+        // Prints the line if formatting succeeded. Only printing it to the domain
+        // of the record decides the result, failed mirrors are ignored.
+        let emit = |formatter: &mut HilogFormatter, record: &Record<'_>, res: io::Result<()>| {
+            let res = res.and_then(|()| {
+                let res = formatter.print(&self.writer, route.log_type, route.level, route.domain, &route.tag, route.private);
+                for domain in self.mirrors(record, &route) {
+                    let _ = formatter.print(&self.writer, route.log_type, route.level, domain, &route.tag, route.private);
+                }
+                res
+            });
 
             // Always clear the buffer afterwards
             formatter.clear();
+            res
+        };
+
+        let print = |formatter: &mut HilogFormatter, record: &Record<'_>| {
+            if let Some(machine) = &self.dual_format {
+                let seq = self.dual_seq.fetch_add(1, Ordering::Relaxed);
+                let res = write!(formatter, "#{} ", seq).and_then(|()| self.format_record(formatter, record, &route));
+                let human = emit(formatter, record, res);
+                let res = write!(formatter, "#{} ", seq).and_then(|()| machine(formatter, record));
+                let machine = emit(formatter, record, res);
+                // The record only counts as emitted if both lines were printed.
+                self.writer.counters().record(human.and(machine));
+            } else {
+                let res = self.format_record(formatter, record, &route);
+                let res = emit(formatter, record, res);
+                self.writer.counters().record(res);
+            }
+
+            if self.shrink_buffers {
+                formatter.shrink_to(SHRUNK_BUFFER_CAPACITY.max(self.buffer_capacity_hint));
+            }
         };
 
         let printed = FORMATTER
@@ -351,10 +2856,12 @@ impl Log for Logger {
                     // There are no active borrows of the buffer
                     if let Some(ref mut formatter) = *tl_buf {
                         // We have a previously set formatter
+                        formatter.reset(&self.writer);
                         print(formatter, record);
                     } else {
                         // We don't have a previously set formatter
                         let mut formatter = HilogFormatter::new(&self.writer);
+                        formatter.reserve(self.buffer_capacity_hint);
                         print(&mut formatter, record);
 
                         *tl_buf = Some(formatter);
@@ -368,11 +2875,359 @@ impl Log for Logger {
 
         if !printed {
             // The thread-local storage was not available (because its
-            // destructor has already run). Create a new single-use
-            // Formatter on the stack for this call.
-            print(&mut HilogFormatter::new(&self.writer), record);
+            // destructor has already run). Reuse the shared shutdown formatter,
+            // so that a noisy shutdown doesn't allocate a buffer per record, or
+            // create a new single-use one if another thread is using it.
+            match SHUTDOWN_FORMATTER.try_lock() {
+                Ok(mut shutdown) => {
+                    let formatter = shutdown.get_or_insert_with(|| HilogFormatter::new(&self.writer));
+                    formatter.reset(&self.writer);
+                    print(formatter, record);
+                }
+                Err(_) => print(&mut HilogFormatter::new(&self.writer), record),
+            }
+        }
+    }
+
+    fn flush(&self) {
+        let _ = self.writer.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::sync::{mpsc, Once, OnceLock};
+    use std::thread;
+    use super::*;
+
+    /// Collects the messages written to it.
+    struct Collect(Arc<Mutex<Vec<String>>>);
+
+    impl Sink for Collect {
+        fn write(&self, _record: &SinkRecord<'_>, msg: &[u8]) -> io::Result<()> {
+            let msg = String::from_utf8_lossy(msg).into_owned();
+            self.0.lock().unwrap_or_else(|e| e.into_inner()).push(msg);
+            Ok(())
+        }
+    }
+
+    fn info(logger: &Logger, msg: &str) {
+        logger.log(&Record::builder().level(Level::Info).target("tls").args(format_args!("{}", msg)).build());
+    }
+
+    thread_local! {
+        static CURRENT: RefCell<Option<Arc<Logger>>> = const { RefCell::new(None) };
+    }
+
+    /// The global logger of the tests, forwarding to the logger current on the thread.
+    struct Current;
+
+    impl Log for Current {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record<'_>) {
+            CURRENT.with(|current| {
+                if let Some(logger) = &*current.borrow() {
+                    logger.log(record);
+                }
+            });
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Builds a logger collecting into `messages`, with `configure` applied.
+    fn collecting(messages: &Arc<Mutex<Vec<String>>>, configure: impl FnOnce(&mut Builder)) -> Arc<Logger> {
+        let mut builder = Builder::new();
+        builder.filter_level(LevelFilter::Info).format_args_only().sink(Collect(Arc::clone(messages)));
+        configure(&mut builder);
+        Arc::new(builder.build())
+    }
+
+    /// Runs `f` with `logger` installed as the target of the `log` macros, on
+    /// another thread, and fails if it doesn't return in time.
+    fn with_current(logger: &Arc<Logger>, f: impl FnOnce(&Logger) + Send + 'static) {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            log::set_logger(&Current).expect("no other logger installed by the tests");
+            log::set_max_level(LevelFilter::Trace);
+        });
+
+        let logger = Arc::clone(logger);
+        let (done, finished) = mpsc::channel();
+        thread::spawn(move || {
+            CURRENT.with(|current| *current.borrow_mut() = Some(Arc::clone(&logger)));
+            f(&logger);
+            let _ = done.send(());
+        });
+        finished
+            .recv_timeout(Duration::from_secs(10))
+            .expect("logging re-entrantly must neither recurse nor deadlock");
+    }
+
+    /// Logs `outer` with a callback of the logger logging `nested`, and checks
+    /// that only the outer record was printed.
+    fn assert_nested_dropped(configure: impl FnOnce(&mut Builder), log: impl FnOnce(&Logger) + Send + 'static) {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let logger = collecting(&messages, configure);
+
+        with_current(&logger, log);
+
+        let messages = messages.lock().unwrap_or_else(|e| e.into_inner());
+        assert!(messages.iter().any(|msg| msg == "outer"), "{:?}", messages);
+        assert!(!messages.iter().any(|msg| msg.contains("nested")), "{:?}", messages);
+        assert_eq!(logger.stats().records_dropped, 1);
+    }
+
+    #[test]
+    fn log_from_format() {
+        assert_nested_dropped(
+            |builder| {
+                builder.format(|buf, record| {
+                    log::info!("nested");
+                    write!(buf, "{}", record.args())
+                });
+            },
+            |logger| info(logger, "outer"),
+        );
+    }
+
+    #[test]
+    fn log_from_gate() {
+        assert_nested_dropped(
+            |builder| {
+                builder.gate(|| {
+                    log::info!("nested");
+                    true
+                });
+            },
+            |logger| info(logger, "outer"),
+        );
+    }
+
+    #[test]
+    fn log_from_on_emit() {
+        assert_nested_dropped(
+            |builder| {
+                builder.on_emit(|_| log::info!("nested"));
+            },
+            |logger| info(logger, "outer"),
+        );
+    }
+
+    #[test]
+    fn log_cstr_from_gate() {
+        assert_nested_dropped(
+            |builder| {
+                builder.gate(|| {
+                    log::info!("nested");
+                    true
+                });
+            },
+            |logger| logger.log_cstr(Level::Info, c"ffi", c"outer"),
+        );
+    }
+
+    #[test]
+    fn message_filters_are_off_by_default() {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let logger = collecting(&messages, |builder| {
+            builder.parse_filters("info/connection");
+        });
+
+        info(&logger, "connection lost");
+        info(&logger, "other");
+
+        assert_eq!(*messages.lock().unwrap(), ["connection lost", "other"]);
+    }
+
+    #[test]
+    fn filter_message_regex() {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let logger = collecting(&messages, |builder| {
+            builder.filter_message_regex("connection");
+        });
+
+        info(&logger, "connection lost");
+        info(&logger, "other");
+
+        assert_eq!(*messages.lock().unwrap(), ["connection lost"]);
+        assert_eq!(logger.stats().records_filtered, 1);
+    }
+
+    #[test]
+    fn log_cstr_applies_the_message_filter() {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let logger = collecting(&messages, |builder| {
+            builder.filter_message_regex("connection");
+        });
+
+        logger.log_cstr(Level::Info, c"ffi", c"connection lost");
+        logger.log_cstr(Level::Info, c"ffi", c"other");
+
+        assert_eq!(*messages.lock().unwrap(), ["connection lost"]);
+    }
+
+    #[test]
+    fn log_cstr_is_truncated_and_validated() {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let logger = collecting(&messages, |builder| {
+            builder.max_formatted_len(Some(8));
+        });
+
+        logger.log_cstr(Level::Info, c"ffi", c"0123456789");
+        logger.log_cstr(Level::Info, c"ffi", c"a\xffb");
+
+        assert_eq!(*messages.lock().unwrap(), ["01234567", "a\u{FFFD}b"]);
+    }
+
+    #[test]
+    fn heartbeat_interval_is_clamped() {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let mut heartbeat = None;
+        let _logger = collecting(&messages, |builder| {
+            heartbeat = Some(builder.heartbeat(Duration::ZERO, Level::Info, "heartbeat").unwrap());
+        });
+
+        thread::sleep(Duration::from_millis(100));
+        heartbeat.unwrap().stop();
+
+        assert_eq!(*messages.lock().unwrap(), ["alive"]);
+    }
+
+    #[test]
+    fn mirrored_record_is_counted_once() {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let logger = collecting(&messages, |builder| {
+            builder.mirror_domains(&[LogDomain::new(0x2000)]);
+        });
+
+        info(&logger, "mirrored");
+
+        assert_eq!(*messages.lock().unwrap(), ["mirrored", "mirrored"]);
+        let stats = logger.stats();
+        assert_eq!(stats.records_emitted, 1);
+        assert_eq!(stats.bytes_written, 2 * "mirrored".len() as u64);
+    }
+
+    #[test]
+    fn failed_record_is_counted_as_dropped() {
+        struct Failing;
+
+        impl Sink for Failing {
+            fn write(&self, _record: &SinkRecord<'_>, _msg: &[u8]) -> io::Result<()> {
+                Err(io::ErrorKind::Other.into())
+            }
+        }
+
+        let mut builder = Builder::new();
+        builder.filter_level(LevelFilter::Info).sink(Failing);
+        let logger = builder.build();
+
+        info(&logger, "lost");
+
+        let stats = logger.stats();
+        assert_eq!((stats.records_emitted, stats.records_dropped, stats.bytes_written), (0, 1, 0));
+    }
+
+    #[test]
+    fn reserved_domains() {
+        assert!(LogDomain::MIN.is_reserved());
+        assert!(LogDomain::SYSTEM_RESERVED_MAX.is_reserved());
+        assert!(!LogDomain::new(LogDomain::SYSTEM_RESERVED_MAX.0 + 1).is_reserved());
+        assert!(!LogDomain::MAX.is_reserved());
+    }
+
+    #[test]
+    fn filter_file_keeps_hash_in_message_filter() {
+        let path = std::env::temp_dir().join(format!("hilog-filters-{}.conf", std::process::id()));
+        fs::write(&path, "# defaults\ninfo # everything\n\n/id#[0-9]+\n").unwrap();
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let logger = collecting(&messages, |builder| {
+            builder.parse_filters_from_file(&path).unwrap().use_message_filters(true);
+        });
+        let _ = fs::remove_file(&path);
+
+        info(&logger, "id#42");
+        info(&logger, "id");
+
+        assert_eq!(*messages.lock().unwrap(), ["id#42"]);
+    }
+
+    #[test]
+    fn log_from_many_threads() {
+        const THREADS: usize = 8;
+        const RECORDS: usize = 500;
+
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let mut builder = Builder::new();
+        builder.filter_level(LevelFilter::Info).format_args_only().sink(Collect(Arc::clone(&messages)));
+        let logger = Arc::new(builder.build());
+
+        let threads: Vec<_> = (0..THREADS)
+            .map(|thread| {
+                let logger = Arc::clone(&logger);
+                thread::spawn(move || {
+                    for record in 0..RECORDS {
+                        // Long enough to notice messages mixed up in a shared buffer.
+                        info(&logger, &format!("thread {} record {} {}", thread, record, "x".repeat(record % 64)));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().expect("logging thread panicked");
         }
+
+        let mut messages = messages.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        let mut expected: Vec<String> = (0..THREADS)
+            .flat_map(|thread| {
+                (0..RECORDS).map(move |record| format!("thread {} record {} {}", thread, record, "x".repeat(record % 64)))
+            })
+            .collect();
+        messages.sort();
+        expected.sort();
+        assert_eq!(messages, expected);
+        assert_eq!(logger.stats().records_emitted, (THREADS * RECORDS) as u64);
     }
 
-    fn flush(&self) {}
-}
\ No newline at end of file
+    #[test]
+    fn log_from_thread_local_destructor() {
+        static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+        struct LogOnDrop;
+
+        impl Drop for LogOnDrop {
+            fn drop(&mut self) {
+                info(LOGGER.get().unwrap(), "from destructor");
+            }
+        }
+
+        thread_local! {
+            static LOG_ON_DROP: LogOnDrop = const { LogOnDrop };
+        }
+
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let mut builder = Builder::new();
+        builder.filter_level(LevelFilter::Info).format_args_only().sink(Collect(Arc::clone(&messages)));
+        let _ = LOGGER.set(builder.build());
+
+        thread::spawn(|| {
+            // Registered before the formatter, so it is destroyed after it.
+            LOG_ON_DROP.with(|_| {});
+            info(LOGGER.get().unwrap(), "before");
+        })
+        .join()
+        .expect("logging from a thread-local destructor must not panic");
+
+        let messages = messages.lock().unwrap_or_else(|e| e.into_inner());
+        assert_eq!(*messages, ["before", "from destructor"]);
+        let stats = LOGGER.get().unwrap().stats();
+        assert_eq!((stats.records_emitted, stats.records_dropped), (2, 0));
+        // Only records logged without thread-local storage use the shutdown formatter.
+        assert!(SHUTDOWN_FORMATTER.lock().unwrap_or_else(|e| e.into_inner()).is_some());
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use crate::sys::LogLevel;
+use crate::LogDomain;
+
+/// Upper bound of cached tags. The cache is cleared when it is exceeded.
+const MAX_TAGS: usize = 256;
+
+/// Caches the results of `OH_LOG_IsLoggable` for a short time.
+pub(crate) struct LoggabilityCache {
+    ttl: Duration,
+    entries: RwLock<HashMap<CString, Vec<Entry>>>,
+}
+
+struct Entry {
+    domain: LogDomain,
+    level: LogLevel,
+    loggable: bool,
+    checked_at: Instant,
+}
+
+impl LoggabilityCache {
+    /// Creates a cache. A `ttl` of zero disables caching.
+    pub(crate) fn new(ttl: Duration) -> Self {
+        LoggabilityCache {
+            ttl,
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the cached loggability, calling `query` if there is no fresh entry.
+    ///
+    /// Hits only take the lock for reading. `query` runs without holding the lock,
+    /// so concurrent misses for the same key may query hilog more than once.
+    pub(crate) fn get_or_query<F>(&self, domain: LogDomain, tag: &CStr, level: LogLevel, query: F) -> bool
+    where
+        F: FnOnce() -> bool,
+    {
+        if self.ttl.is_zero() {
+            return query();
+        }
+
+        let now = Instant::now();
+        {
+            let entries = self.entries.read().unwrap_or_else(|e| e.into_inner());
+            if let Some(entry) = entries.get(tag).and_then(|entries| {
+                entries.iter().find(|e| e.domain == domain && e.level == level)
+            }) {
+                if now.duration_since(entry.checked_at) < self.ttl {
+                    return entry.loggable;
+                }
+            }
+        }
+
+        let loggable = query();
+
+        let mut entries = self.entries.write().unwrap_or_else(|e| e.into_inner());
+        if entries.len() >= MAX_TAGS && !entries.contains_key(tag) {
+            entries.clear();
+        }
+        let tag_entries = entries.entry(tag.to_owned()).or_default();
+        tag_entries.retain(|e| e.domain != domain || e.level != level);
+        tag_entries.push(Entry { domain, level, loggable, checked_at: now });
+
+        loggable
+    }
+
+    /// Removes all cached results.
+    pub(crate) fn clear(&self) {
+        self.entries.write().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+}
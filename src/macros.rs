@@ -0,0 +1,24 @@
+/// Logs with a tag fixed at compile time.
+///
+/// Forwards to the given `log` macro with `tag` as the `target:`, so that no tag
+/// has to be derived at runtime. Combined with [`Builder::tag_from_target`] the
+/// record is printed with `tag` as its hilog tag. The tag is checked against
+/// the constraints of [`Tag`] at compile time.
+///
+/// [`Builder::tag_from_target`]: crate::Builder::tag_from_target
+/// [`Tag`]: crate::Tag
+///
+/// # Examples
+///
+/// ```
+/// use hilog::hilog_tagged;
+///
+/// hilog_tagged!(tag: "Net", info!("connected to {}", "example.com"));
+/// ```
+#[macro_export]
+macro_rules! hilog_tagged {
+    (tag: $tag:literal, $level:ident!($($arg:tt)+)) => {{
+        const _: () = assert!($crate::__private::is_valid_tag($tag), "invalid hilog tag");
+        $crate::__private::log::$level!(target: $tag, $($arg)+)
+    }};
+}
@@ -1,20 +1,78 @@
-use std::{io, mem};
+use std::{env, fmt, fs, io, mem};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::fmt::Display;
 use std::io::Write;
-use log::Record;
-use crate::ohfmt::{HilogFormatFn, HilogFormatter, TimestampPrecision};
+use std::path::Path;
+use std::time::{Instant, SystemTime};
+use crate::sys::LogLevel;
+use log::{Level, Record};
+use crate::ohfmt::{FileMode, HilogContextFormatFn, HilogFormatFn, HilogFormatter, TaskIdFn, TimestampPrecision, TimestampSourceFn};
+use crate::{context, LevelMapFn};
+use crate::ohfmt::timestamp::{self, Timestamp};
+
+/// Upper bound for the indentation of continuation lines.
+pub(crate) const MAX_INDENT: usize = 64;
 
 pub(crate) struct Builder {
     pub(crate) format_timestamp: Option<TimestampPrecision>,
     pub(crate) format_module_path: bool,
+    pub(crate) format_module_abbrev: bool,
     pub(crate) format_target: bool,
     pub(crate) format_level: bool,
     pub(crate) format_indent: Option<usize>,
     pub(crate) custom_format: Option<HilogFormatFn>,
+    pub(crate) custom_context_format: Option<HilogContextFormatFn>,
     pub(crate) format_suffix: &'static str,
+    pub(crate) format_header_separator: &'static str,
+    pub(crate) format_component: Option<&'static str>,
+    pub(crate) format_collapse_whitespace: bool,
+    pub(crate) format_quote_message: bool,
+    pub(crate) timestamp_source: Option<TimestampSourceFn>,
+    pub(crate) format_level_style: LevelStyle,
+    pub(crate) format_level_glyphs: [&'static str; 5],
+    pub(crate) format_header_brackets: bool,
+    pub(crate) format_indent_char: char,
+    pub(crate) format_delta: bool,
+    pub(crate) format_uptime: bool,
+    pub(crate) format_timestamp_date_on_change: bool,
+    pub(crate) format_timestamp_human: bool,
+    pub(crate) task_id_fn: Option<TaskIdFn>,
+    pub(crate) format_correlation_id: bool,
+    pub(crate) format_ndjson: bool,
+    pub(crate) format_default_kv: Vec<(String, String)>,
+    pub(crate) format_context: bool,
+    pub(crate) format_process_name: bool,
+    pub(crate) format_level_padded: bool,
+    // Copied from the logger builder, to write numeric levels.
+    pub(crate) level_map: Option<LevelMapFn>,
+    pub(crate) format_file: Option<FileMode>,
+    #[cfg(feature = "binary")]
+    pub(crate) format_cbor: bool,
     built: bool,
 }
 
+thread_local! {
+    /// When the default format last wrote a record with a delta on this thread.
+    static LAST_RECORD: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// How the level is written in the default format.
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum LevelStyle {
+    /// The full level name, e.g. `INFO`.
+    Name,
+    /// The first letter of the level name, e.g. `I`.
+    Letter,
+    /// The numeric value of the hilog level, e.g. `4`.
+    Numeric,
+    /// A symbol per level, e.g. `●`.
+    Glyph,
+}
+
+/// The glyphs of the levels from `Error` to `Trace`.
+pub(crate) const DEFAULT_LEVEL_GLYPHS: [&str; 5] = ["■", "▲", "●", "○", "·"];
+
 impl Builder {
     /// Convert the format into a callable function.
     ///
@@ -24,7 +82,7 @@ impl Builder {
     pub(crate) fn build(&mut self) -> HilogFormatFn {
         assert!(!self.built, "attempt to re-use consumed builder");
 
-        let built = mem::replace(
+        let mut built = mem::replace(
             self,
             Builder {
                 built: true,
@@ -35,15 +93,66 @@ impl Builder {
         if let Some(fmt) = built.custom_format {
             fmt
         } else {
+            let timestamp_source = built
+                .timestamp_source
+                .take()
+                .unwrap_or_else(|| Box::new(SystemTime::now));
+
+            if built.format_ndjson {
+                return Box::new(move |buf, record| {
+                    let now = timestamp::now(&*timestamp_source);
+                    crate::ohfmt::ndjson::write(buf, record, now, &built.format_default_kv)
+                });
+            }
+
+            #[cfg(feature = "binary")]
+            if built.format_cbor {
+                return Box::new(move |buf, record| {
+                    let now = timestamp::now(&*timestamp_source);
+                    crate::ohfmt::cbor::write(buf, record, now, &built.format_default_kv)
+                });
+            }
+
+            // Read once, the name doesn't change during the lifetime of the process.
+            let process_name = built.format_process_name.then(process_name).flatten();
+
+            // Uptime is measured from when the logger is built.
+            let uptime_start = built.format_uptime.then(Instant::now);
+
+            // The day of the last timestamp, shared by all threads using this format.
+            let last_day = AtomicU64::new(u64::MAX);
+
             Box::new(move |buf, record| {
                 let fmt = DefaultFormat {
                     timestamp: built.format_timestamp,
+                    timestamp_source: &*timestamp_source,
+                    last_day: built.format_timestamp_date_on_change.then_some(&last_day),
+                    timestamp_human: built.format_timestamp_human,
                     module_path: built.format_module_path,
+                    module_abbrev: built.format_module_abbrev,
                     target: built.format_target,
                     level: built.format_level,
                     written_header_value: false,
                     indent: built.format_indent,
                     suffix: built.format_suffix,
+                    header_separator: built.format_header_separator,
+                    component: built.format_component,
+                    collapse_whitespace: built.format_collapse_whitespace,
+                    quote_message: built.format_quote_message,
+                    level_style: built.format_level_style,
+                    level_glyphs: built.format_level_glyphs,
+                    header_brackets: built.format_header_brackets,
+                    indent_char: built.format_indent_char,
+                    delta: built.format_delta,
+                    uptime_start,
+                    task_id_fn: built.task_id_fn.as_deref(),
+                    correlation_id: built.format_correlation_id,
+                    default_kv: &built.format_default_kv,
+                    context: built.format_context,
+                    process_name: process_name.as_deref(),
+                    level_padded: built.format_level_padded,
+                    level_map: built.level_map.as_deref(),
+                    file: built.format_file,
                     buf,
                 };
 
@@ -53,31 +162,105 @@ impl Builder {
     }
 }
 
+/// Returns the name of the current process.
+///
+/// Uses `/proc/self/comm` if available, otherwise the file name of the executable.
+fn process_name() -> Option<String> {
+    if let Ok(comm) = fs::read_to_string("/proc/self/comm") {
+        let comm = comm.trim_end();
+        if !comm.is_empty() {
+            return Some(comm.to_owned());
+        }
+    }
+
+    env::args_os()
+        .next()
+        .as_deref()
+        .map(Path::new)
+        .and_then(Path::file_name)
+        .map(|name| name.to_string_lossy().into_owned())
+}
+
 type SubtleStyle = &'static str;
 
+/// Writes a module path with all but the last segment cut to their first letter,
+/// e.g. `m::n::connection` for `my_app::net::connection`.
+struct AbbrevPath<'a>(&'a str);
+
+impl Display for AbbrevPath<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut segments = self.0.rsplit("::");
+        let last = segments.next().unwrap_or_default();
+        let parents = self.0[..self.0.len() - last.len()].split("::").filter(|s| !s.is_empty());
+        for segment in parents {
+            if let Some(first) = segment.chars().next() {
+                write!(f, "{}::", first)?;
+            }
+        }
+        f.write_str(last)
+    }
+}
+
 /// The default format.
 ///
 /// This format needs to work with any combination of crate features.
 struct DefaultFormat<'a> {
     timestamp: Option<TimestampPrecision>,
+    timestamp_source: &'a (dyn Fn() -> SystemTime + Sync + Send),
+    timestamp_human: bool,
+    // Set if the date is only written when the day changed.
+    last_day: Option<&'a AtomicU64>,
     module_path: bool,
+    module_abbrev: bool,
     target: bool,
     level: bool,
     written_header_value: bool,
     indent: Option<usize>,
     buf: &'a mut HilogFormatter,
     suffix: &'a str,
+    header_separator: &'a str,
+    component: Option<&'a str>,
+    collapse_whitespace: bool,
+    quote_message: bool,
+    level_style: LevelStyle,
+    level_glyphs: [&'static str; 5],
+    header_brackets: bool,
+    indent_char: char,
+    delta: bool,
+    uptime_start: Option<Instant>,
+    task_id_fn: Option<&'a (dyn Fn() -> Option<u64> + Sync + Send)>,
+    correlation_id: bool,
+    default_kv: &'a [(String, String)],
+    context: bool,
+    process_name: Option<&'a str>,
+    level_padded: bool,
+    level_map: Option<&'a (dyn Fn(Level) -> LogLevel + Sync + Send)>,
+    file: Option<FileMode>,
 }
 
 impl<'a> DefaultFormat<'a> {
     fn write(mut self, record: &Record<'_>) -> io::Result<()> {
         self.write_timestamp()?;
+        self.write_uptime()?;
+        self.write_delta()?;
         self.write_level(record)?;
+        self.write_process_name()?;
+        self.write_component()?;
+        self.write_task_id()?;
+        self.write_correlation_id()?;
         self.write_module_path(record)?;
         self.write_target(record)?;
+        self.write_file(record)?;
         self.finish_header()?;
 
-        self.write_args(record)?;
+        if self.quote_message {
+            self.buf.write_all(b"\"")?;
+            self.write_args(record)?;
+            self.buf.write_all(b"\"")?;
+        } else {
+            self.write_args(record)?;
+        }
+        self.write_context()?;
         write!(self.buf, "{}", self.suffix)
     }
 
@@ -92,7 +275,7 @@ impl<'a> DefaultFormat<'a> {
         if !self.written_header_value {
             self.written_header_value = true;
 
-            let open_brace = self.subtle_style("[");
+            let open_brace = if self.header_brackets { self.subtle_style("[") } else { "" };
             write!(self.buf, "{}{}", open_brace, value)
         } else {
             write!(self.buf, " {}", value)
@@ -106,12 +289,126 @@ impl<'a> DefaultFormat<'a> {
 
         let level = record.level();
 
-        self.write_header_value(format_args!("{:<5}", level))
+        match self.level_style {
+            LevelStyle::Name if self.level_padded => self.write_header_value(format_args!("{:<5}", level)),
+            LevelStyle::Name => self.write_header_value(level),
+            LevelStyle::Letter => {
+                let letter = &level.as_str()[..1];
+                self.write_header_value(letter)
+            }
+            LevelStyle::Numeric => {
+                let native = match self.level_map {
+                    Some(level_map) => level_map(level),
+                    None => level.into(),
+                };
+                self.write_header_value(native.0)
+            }
+            LevelStyle::Glyph => {
+                // `Level` starts at 1 for `Error`.
+                match self.level_glyphs[level as usize - 1] {
+                    "" => self.write_header_value(&level.as_str()[..1]),
+                    glyph => self.write_header_value(glyph),
+                }
+            }
+        }
     }
 
     fn write_timestamp(&mut self) -> io::Result<()> {
-        let _ = self.timestamp;
-        Ok(())
+        let precision = match self.timestamp {
+            Some(precision) => precision,
+            None => return Ok(()),
+        };
+
+        let timestamp = Timestamp::new(timestamp::now(self.timestamp_source), precision);
+        if self.timestamp_human {
+            return self.write_header_value(timestamp.human());
+        }
+        match self.last_day {
+            Some(last_day) if last_day.swap(timestamp.day(), Ordering::Relaxed) == timestamp.day() => {
+                self.write_header_value(timestamp.time_only())
+            }
+            _ => self.write_header_value(timestamp),
+        }
+    }
+
+    fn write_uptime(&mut self) -> io::Result<()> {
+        let Some(start) = self.uptime_start else {
+            return Ok(());
+        };
+
+        // Formatted by hand into a buffer on the stack, since this is written on
+        // every line. `u64::MAX` seconds have 20 digits.
+        let uptime = start.elapsed();
+        let mut digits = [0u8; 26];
+        let mut pos = digits.len();
+        let mut push = |digit: u8| {
+            pos -= 1;
+            digits[pos] = digit;
+        };
+
+        push(b's');
+        let mut millis = uptime.subsec_millis();
+        for _ in 0..3 {
+            push(b'0' + (millis % 10) as u8);
+            millis /= 10;
+        }
+        push(b'.');
+        let mut secs = uptime.as_secs();
+        loop {
+            push(b'0' + (secs % 10) as u8);
+            secs /= 10;
+            if secs == 0 {
+                break;
+            }
+        }
+
+        // Only ASCII digits, `.` and `s` were written.
+        let uptime = std::str::from_utf8(&digits[pos..]).unwrap_or_default();
+        self.write_header_value(uptime)
+    }
+
+    fn write_delta(&mut self) -> io::Result<()> {
+        if !self.delta {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let delta = LAST_RECORD
+            .try_with(|last| last.replace(Some(now)))
+            .ok()
+            .flatten()
+            .map_or(0.0, |last| now.duration_since(last).as_secs_f64() * 1000.0);
+        self.write_header_value(format_args!("+{:.1}ms", delta))
+    }
+
+    fn write_process_name(&mut self) -> io::Result<()> {
+        match self.process_name {
+            Some(process_name) => self.write_header_value(process_name),
+            None => Ok(()),
+        }
+    }
+
+    fn write_component(&mut self) -> io::Result<()> {
+        match self.component {
+            Some(component) => self.write_header_value(component),
+            None => Ok(()),
+        }
+    }
+
+    fn write_task_id(&mut self) -> io::Result<()> {
+        match self.task_id_fn.and_then(|task_id_fn| task_id_fn()) {
+            Some(task_id) => self.write_header_value(format_args!("task={}", task_id)),
+            None => Ok(()),
+        }
+    }
+
+    fn write_correlation_id(&mut self) -> io::Result<()> {
+        if !self.correlation_id {
+            return Ok(());
+        }
+
+        context::with_correlation_id(|id| self.write_header_value(format_args!("cid={}", id)))
+            .unwrap_or(Ok(()))
     }
 
     fn write_module_path(&mut self, record: &Record<'_>) -> io::Result<()> {
@@ -120,7 +417,11 @@ impl<'a> DefaultFormat<'a> {
         }
 
         if let Some(module_path) = record.module_path() {
-            self.write_header_value(module_path)
+            if self.module_abbrev {
+                self.write_header_value(AbbrevPath(module_path))
+            } else {
+                self.write_header_value(module_path)
+            }
         } else {
             Ok(())
         }
@@ -131,48 +432,131 @@ impl<'a> DefaultFormat<'a> {
             return Ok(());
         }
 
-        match record.target() {
+        match crate::strip_target_markers(record.target()) {
             "" => Ok(()),
             target => self.write_header_value(target),
         }
     }
 
+    fn write_file(&mut self, record: &Record<'_>) -> io::Result<()> {
+        let (Some(mode), Some(file)) = (self.file, record.file()) else {
+            return Ok(());
+        };
+
+        let file = mode.apply(file);
+        match record.line() {
+            Some(line) => self.write_header_value(format_args!("{}:{}", file, line)),
+            None => self.write_header_value(file),
+        }
+    }
+
     fn finish_header(&mut self) -> io::Result<()> {
         if self.written_header_value {
-            let close_brace = self.subtle_style("]");
-            write!(self.buf, "{} ", close_brace)
+            let close_brace = if self.header_brackets { self.subtle_style("]") } else { "" };
+            write!(self.buf, "{}{}", close_brace, self.header_separator)
         } else {
             Ok(())
         }
     }
 
+    fn write_context(&mut self) -> io::Result<()> {
+        if !self.context {
+            return Ok(());
+        }
+
+        let buf = &mut *self.buf;
+        context::for_each_with_defaults(self.default_kv, |key, value| write!(buf, " {}={}", key, value))
+    }
+
     fn write_args(&mut self, record: &Record<'_>) -> io::Result<()> {
-        match self.indent {
+        match (self.indent, self.collapse_whitespace, self.quote_message) {
             // Fast path for no indentation
-            None => write!(self.buf, "{}", record.args()),
+            (None, false, false) => write!(self.buf, "{}", record.args()),
 
-            Some(indent_count) => {
-                // Create a wrapper around the buffer only if we have to actually indent the message
+            (indent, collapse_whitespace, quote_message) => {
+                // Create a wrapper around the buffer only if we have to actually
+                // indent, collapse or escape the message
 
-                struct IndentWrapper<'a, 'b> {
+                struct ArgsWrapper<'a, 'b> {
                     fmt: &'a mut DefaultFormat<'b>,
-                    indent_count: usize,
+                    indent: Option<usize>,
+                    collapse_whitespace: bool,
+                    quote_message: bool,
+                    in_whitespace: bool,
+                }
+
+                impl<'a, 'b> ArgsWrapper<'a, 'b> {
+                    fn write_indent(&mut self, indent_count: usize) -> io::Result<()> {
+                        if self.fmt.indent_char == ' ' {
+                            return write!(
+                                self.fmt.buf,
+                                "{}{:width$}",
+                                self.fmt.suffix,
+                                "",
+                                width = indent_count
+                            );
+                        }
+
+                        write!(self.fmt.buf, "{}", self.fmt.suffix)?;
+                        let mut utf8 = [0; 4];
+                        let fill = self.fmt.indent_char.encode_utf8(&mut utf8).as_bytes();
+                        for _ in 0..indent_count {
+                            self.fmt.buf.write_all(fill)?;
+                        }
+                        Ok(())
+                    }
+
+                    fn write_text(&mut self, text: &[u8]) -> io::Result<()> {
+                        if !self.quote_message {
+                            return self.fmt.buf.write_all(text);
+                        }
+
+                        // Escape quotes and backslashes, so the closing quote is unambiguous.
+                        let mut start = 0;
+                        for (i, &b) in text.iter().enumerate() {
+                            if b == b'"' || b == b'\\' {
+                                self.fmt.buf.write_all(&text[start..i])?;
+                                self.fmt.buf.write_all(&[b'\\', b])?;
+                                start = i + 1;
+                            }
+                        }
+                        self.fmt.buf.write_all(&text[start..])
+                    }
+
+                    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+                        if !self.collapse_whitespace {
+                            return self.write_text(line);
+                        }
+
+                        let mut start = 0;
+                        for (i, &b) in line.iter().enumerate() {
+                            if b == b' ' || b == b'\t' {
+                                self.write_text(&line[start..i])?;
+                                if !self.in_whitespace {
+                                    self.fmt.buf.write_all(b" ")?;
+                                }
+                                self.in_whitespace = true;
+                                start = i + 1;
+                            } else {
+                                self.in_whitespace = false;
+                            }
+                        }
+                        self.write_text(&line[start..])
+                    }
                 }
 
-                impl<'a, 'b> Write for IndentWrapper<'a, 'b> {
+                impl<'a, 'b> Write for ArgsWrapper<'a, 'b> {
                     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
                         let mut first = true;
                         for chunk in buf.split(|&x| x == b'\n') {
                             if !first {
-                                write!(
-                                    self.fmt.buf,
-                                    "{}{:width$}",
-                                    self.fmt.suffix,
-                                    "",
-                                    width = self.indent_count
-                                )?;
+                                match self.indent {
+                                    Some(indent_count) => self.write_indent(indent_count)?,
+                                    None => self.fmt.buf.write_all(b"\n")?,
+                                }
+                                self.in_whitespace = false;
                             }
-                            self.fmt.buf.write_all(chunk)?;
+                            self.write_line(chunk)?;
                             first = false;
                         }
 
@@ -186,9 +570,12 @@ impl<'a> DefaultFormat<'a> {
 
                 // The explicit scope here is just to make older versions of Rust happy
                 {
-                    let mut wrapper = IndentWrapper {
+                    let mut wrapper = ArgsWrapper {
                         fmt: self,
-                        indent_count,
+                        indent,
+                        collapse_whitespace,
+                        quote_message,
+                        in_whitespace: false,
                     };
                     write!(wrapper, "{}", record.args())?;
                 }
@@ -202,14 +589,185 @@ impl<'a> DefaultFormat<'a> {
 impl Default for Builder {
     fn default() -> Self {
         Builder {
-            format_timestamp: Some(Default::default()),
+            // hilog already records a timestamp for every entry.
+            format_timestamp: None,
             format_module_path: false,
+            format_module_abbrev: false,
             format_target: true,
             format_level: true,
             format_indent: Some(4),
             custom_format: None,
+            custom_context_format: None,
             format_suffix: "\n",
+            format_header_separator: " ",
+            format_component: None,
+            format_collapse_whitespace: false,
+            format_quote_message: false,
+            timestamp_source: None,
+            format_level_style: LevelStyle::Name,
+            format_level_glyphs: DEFAULT_LEVEL_GLYPHS,
+            format_header_brackets: true,
+            format_indent_char: ' ',
+            format_delta: false,
+            format_uptime: false,
+            format_timestamp_date_on_change: false,
+            format_timestamp_human: false,
+            task_id_fn: None,
+            format_correlation_id: false,
+            format_ndjson: false,
+            format_default_kv: Vec::new(),
+            format_context: true,
+            format_process_name: false,
+            format_level_padded: true,
+            level_map: None,
+            format_file: None,
+            #[cfg(feature = "binary")]
+            format_cbor: false,
             built: false,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+    use crate::ohfmt::writer::HilogWriter;
+    use super::*;
+
+    /// Formats a record of `level` with `args` using the format built from `builder`.
+    fn format(builder: &mut Builder, level: Level, args: fmt::Arguments<'_>) -> String {
+        let format = builder.build();
+        let writer = HilogWriter::default();
+        let mut buf = HilogFormatter::new(&writer);
+        let record = Record::builder().level(level).target("my_app").args(args).build();
+
+        format(&mut buf, &record).unwrap();
+        String::from_utf8(buf.as_bytes().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn numeric_level_of_every_level() {
+        let levels = [
+            (Level::Error, "[6] failed\n"),
+            (Level::Warn, "[5] failed\n"),
+            (Level::Info, "[4] failed\n"),
+            (Level::Debug, "[3] failed\n"),
+            // hilog has no trace level, trace records are printed as debug.
+            (Level::Trace, "[3] failed\n"),
+        ];
+
+        for (level, expected) in levels {
+            let mut builder = Builder {
+                format_level_style: LevelStyle::Numeric,
+                format_target: false,
+                ..Default::default()
+            };
+            assert_eq!(format(&mut builder, level, format_args!("failed")), expected);
+        }
+    }
+
+    #[test]
+    fn empty_header_separator() {
+        let mut builder = Builder {
+            format_header_separator: "",
+            format_target: false,
+            format_level_padded: false,
+            format_suffix: "",
+            ..Default::default()
+        };
+        assert_eq!(format(&mut builder, Level::Info, format_args!("message")), "[INFO]message");
+
+        let mut builder = Builder {
+            format_header_separator: " | ",
+            format_target: false,
+            format_level_padded: false,
+            format_suffix: "",
+            ..Default::default()
+        };
+        assert_eq!(format(&mut builder, Level::Info, format_args!("message")), "[INFO] | message");
+    }
+
+    #[test]
+    fn uptime_does_not_allocate() {
+        let mut builder = Builder {
+            format_uptime: true,
+            format_level: false,
+            format_target: false,
+            ..Default::default()
+        };
+        let format = builder.build();
+        let writer = HilogWriter::default();
+        let mut buf = HilogFormatter::new(&writer);
+        buf.reserve(64);
+
+        let allocations = alloc::count(|| {
+            for _ in 0..100 {
+                format(&mut buf, &Record::builder().args(format_args!("tick")).build()).unwrap();
+                buf.clear();
+            }
+        });
+
+        assert_eq!(allocations, 0);
+        format(&mut buf, &Record::builder().args(format_args!("tick")).build()).unwrap();
+        let formatted = std::str::from_utf8(buf.as_bytes()).unwrap();
+        assert!(formatted.starts_with("[0.0") && formatted.ends_with("s] tick\n"), "{}", formatted);
+    }
+
+    #[test]
+    fn large_indent_respects_the_buffer_limit() {
+        let mut builder = Builder {
+            format_indent: Some(4096),
+            ..Default::default()
+        };
+
+        let formatted = format(&mut builder, Level::Info, format_args!("first\nsecond\nthird"));
+
+        assert!(formatted.starts_with("[INFO  my_app] first\n"));
+        assert!(formatted.len() <= crate::ohfmt::writer::DEFAULT_MAX_FORMATTED_LEN);
+        assert!(!formatted.contains("third"));
+    }
+
+    #[test]
+    fn indent_is_capped() {
+        let mut builder = crate::Builder::new();
+        builder.format_indent(Some(4096));
+
+        let formatted = format(&mut builder.format, Level::Info, format_args!("first\nsecond"));
+
+        let indent = " ".repeat(MAX_INDENT);
+        assert_eq!(formatted, format!("[INFO  my_app] first\n{}second\n", indent));
+    }
+
+    /// Counts the heap allocations of the current thread.
+    mod alloc {
+        use std::alloc::{GlobalAlloc, Layout, System};
+        use std::cell::Cell;
+
+        thread_local! {
+            static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+        }
+
+        struct Counting;
+
+        unsafe impl GlobalAlloc for Counting {
+            unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+                let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+                System.alloc(layout)
+            }
+
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                System.dealloc(ptr, layout)
+            }
+        }
+
+        #[global_allocator]
+        static COUNTING: Counting = Counting;
+
+        /// Returns the number of allocations `f` made on this thread.
+        pub(super) fn count(f: impl FnOnce()) -> usize {
+            let before = ALLOCATIONS.with(Cell::get);
+            f();
+            ALLOCATIONS.with(Cell::get) - before
+        }
+    }
+}
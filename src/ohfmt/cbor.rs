@@ -0,0 +1,179 @@
+//! A minimal CBOR (RFC 8949) encoder for log records.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::Record;
+use crate::{context, hex};
+use crate::ohfmt::{HilogFormatter, Unlimited};
+
+const MAJOR_UNSIGNED: u8 = 0;
+const MAJOR_TEXT: u8 = 3;
+const MAJOR_MAP: u8 = 5;
+
+/// Writes `record` as a hex encoded CBOR map.
+///
+/// The map contains the keys `level`, `target`, `timestamp` (milliseconds since
+/// the unix epoch), `message` and `kvs`, in this order. `kvs` is a map with
+/// `default_kv` and the context of the thread, which overrides defaults with
+/// the same key.
+///
+/// Only the message is cut to stay within the size limit of the formatter, so
+/// that the map is always a complete data item. If everything but the message
+/// exceeds the limit, the map is written in full anyway.
+pub(crate) fn write(
+    buf: &mut HilogFormatter,
+    record: &Record<'_>,
+    now: SystemTime,
+    default_kv: &[(String, String)],
+) -> io::Result<()> {
+    let timestamp = now
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| u64::try_from(d.as_millis()).unwrap_or(u64::MAX));
+
+    // The length of a map comes first, so the pairs are collected beforehand.
+    let mut kvs = Vec::new();
+    context::for_each_with_defaults(default_kv, |key, value| {
+        kvs.push((key.to_owned(), value.to_owned()));
+        Ok(())
+    })?;
+
+    let mut head = Vec::with_capacity(64);
+    write_head(&mut head, MAJOR_MAP, 5);
+    write_text(&mut head, "level");
+    write_text(&mut head, record.level().as_str());
+    write_text(&mut head, "target");
+    write_text(&mut head, crate::strip_target_markers(record.target()));
+    write_text(&mut head, "timestamp");
+    write_head(&mut head, MAJOR_UNSIGNED, timestamp);
+    write_text(&mut head, "message");
+
+    let mut tail = Vec::with_capacity(64);
+    write_text(&mut tail, "kvs");
+    write_head(&mut tail, MAJOR_MAP, kvs.len() as u64);
+    for (key, value) in &kvs {
+        write_text(&mut tail, key);
+        write_text(&mut tail, value);
+    }
+
+    // Every byte takes two hex digits, and the head of the message at most 9 bytes.
+    let budget = (buf.remaining() / 2).saturating_sub(head.len() + 9 + tail.len());
+    let mut message = Bounded {
+        text: String::new(),
+        budget,
+    };
+    let _ = fmt::write(&mut message, *record.args());
+
+    let mut cbor = head;
+    write_text(&mut cbor, &message.text);
+    cbor.extend_from_slice(&tail);
+
+    write!(Unlimited(buf), "{}", hex(&cbor))
+}
+
+/// Collects a string of at most `budget` bytes, discarding the rest.
+struct Bounded {
+    text: String,
+    budget: usize,
+}
+
+impl fmt::Write for Bounded {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut end = s.len().min(self.budget);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.text.push_str(&s[..end]);
+        // Nothing more is written once the budget is exceeded.
+        self.budget = if end < s.len() { 0 } else { self.budget - end };
+        Ok(())
+    }
+}
+
+fn write_text(cbor: &mut Vec<u8>, text: &str) {
+    write_head(cbor, MAJOR_TEXT, text.len() as u64);
+    cbor.extend_from_slice(text.as_bytes());
+}
+
+/// Writes the initial byte of a data item and its argument in the shortest form.
+fn write_head(cbor: &mut Vec<u8>, major: u8, value: u64) {
+    let major = major << 5;
+    if value < 24 {
+        cbor.push(major | value as u8);
+    } else if let Ok(value) = u8::try_from(value) {
+        cbor.extend_from_slice(&[major | 24, value]);
+    } else if let Ok(value) = u16::try_from(value) {
+        cbor.push(major | 25);
+        cbor.extend_from_slice(&value.to_be_bytes());
+    } else if let Ok(value) = u32::try_from(value) {
+        cbor.push(major | 26);
+        cbor.extend_from_slice(&value.to_be_bytes());
+    } else {
+        cbor.push(major | 27);
+        cbor.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+    use crate::ohfmt::writer::{HilogWriter, DEFAULT_MAX_FORMATTED_LEN};
+    use super::*;
+
+    /// Returns the length of the data item at the start of `cbor`, if it is a
+    /// well-formed unsigned integer, text or map.
+    fn item_len(cbor: &[u8]) -> Option<usize> {
+        let (&initial, rest) = cbor.split_first()?;
+        let (value, head) = match initial & 0x1f {
+            info @ 0..=23 => (u64::from(info), 1),
+            info @ 24..=27 => {
+                let n = 1 << (info - 24);
+                let bytes = rest.get(..n)?;
+                (bytes.iter().fold(0, |value, &b| value << 8 | u64::from(b)), 1 + n)
+            }
+            _ => return None,
+        };
+        let value = usize::try_from(value).ok()?;
+        match initial >> 5 {
+            MAJOR_UNSIGNED => Some(head),
+            MAJOR_TEXT => {
+                std::str::from_utf8(cbor.get(head..head + value)?).ok()?;
+                Some(head + value)
+            }
+            MAJOR_MAP => {
+                let mut len = head;
+                for _ in 0..value * 2 {
+                    len += item_len(&cbor[len..])?;
+                }
+                Some(len)
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn long_message_is_cut_within_the_map() {
+        let writer = HilogWriter::default();
+        let mut buf = HilogFormatter::new(&writer);
+        let message = "é".repeat(5000);
+        let kv = [("service".to_owned(), "auth".to_owned())];
+
+        write(
+            &mut buf,
+            &Record::builder().level(Level::Info).target("my_app").args(format_args!("{}", message)).build(),
+            UNIX_EPOCH,
+            &kv,
+        )
+        .unwrap();
+
+        let hex = std::str::from_utf8(buf.as_bytes()).unwrap();
+        assert!((DEFAULT_MAX_FORMATTED_LEN - 32..=DEFAULT_MAX_FORMATTED_LEN).contains(&hex.len()), "{}", hex.len());
+        let cbor: Vec<u8> = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect();
+        assert_eq!(item_len(&cbor), Some(cbor.len()));
+        // The pairs after the message are still there.
+        assert!(cbor.ends_with(b"ckvs\xa1gservicedauth"), "{}", hex);
+    }
+}
@@ -1,15 +1,17 @@
 pub mod writer;
 pub(crate) mod builder;
+#[cfg(feature = "binary")]
+mod cbor;
+mod ndjson;
+pub(crate) mod timestamp;
 
-use std::cell::RefCell;
-use std::ffi::{CStr, CString};
-use std::{fmt, io, mem};
-use std::fmt::Display;
+use std::ffi::CStr;
+use std::{fmt, io};
 use std::io::Write;
-use std::rc::Rc;
-use hilog_sys::LogLevel;
+use std::time::SystemTime;
+use crate::sys::{LogLevel, LogType};
 use log::Record;
-use crate::{hilog_log, LogDomain};
+use crate::LogDomain;
 use writer::HilogWriter;
 
 /// Formatting precision of timestamps.
@@ -37,40 +39,210 @@ impl Default for TimestampPrecision {
     }
 }
 
+/// How the source file of a record is written.
+#[allow(clippy::exhaustive_enums)] // compatibility
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FileMode {
+    /// The path as passed to the compiler, which may be absolute.
+    Full,
+    /// Only the file name, e.g. `lib.rs`.
+    FileNameOnly,
+    /// The path relative to the root of the crate, e.g. `src/net/mod.rs`.
+    RelativeToCrate,
+}
+
+impl FileMode {
+    /// Returns the part of `file` to write.
+    pub(crate) fn apply(self, file: &str) -> &str {
+        match self {
+            FileMode::Full => file,
+            FileMode::FileNameOnly => file.rsplit(['/', '\\']).next().unwrap_or(file),
+            FileMode::RelativeToCrate => {
+                // Cargo passes paths relative to the workspace root for local crates
+                // and absolute paths for dependencies, both end in `src/...`.
+                match file.rfind("/src/").or_else(|| file.rfind("\\src\\")) {
+                    Some(pos) => &file[pos + 1..],
+                    None => file,
+                }
+            }
+        }
+    }
+}
+
+/// Whether to style the output, for compatibility with `env_logger`.
+///
+/// hilog has no notion of terminal colors, so all variants behave the same and
+/// no styling is ever written. Escape sequences written by a custom format are
+/// removed before a message is passed to hilog, while a [`Sink`](crate::Sink)
+/// receives the message as formatted, e.g. to keep colors on a terminal.
+#[allow(clippy::exhaustive_enums)] // compatibility
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum WriteStyle {
+    /// Style the output if the destination supports it.
+    #[default]
+    Auto,
+    /// Always style the output.
+    Always,
+    /// Never style the output.
+    Never,
+}
+
 pub type HilogFormatFn = Box<dyn Fn(&mut HilogFormatter, &Record<'_>) -> io::Result<()> + Sync + Send>;
+pub type HilogContextFormatFn =
+    Box<dyn Fn(&mut HilogFormatter, &Record<'_>, &FormatContext<'_>) -> io::Result<()> + Sync + Send>;
+pub(crate) type TimestampSourceFn = Box<dyn Fn() -> SystemTime + Sync + Send>;
+pub(crate) type TaskIdFn = Box<dyn Fn() -> Option<u64> + Sync + Send>;
+
+/// What the logger determined about a record, passed to [`Builder::format_with_context`].
+///
+/// [`Builder::format_with_context`]: crate::Builder::format_with_context
+pub struct FormatContext<'a> {
+    domain: LogDomain,
+    tag: &'a CStr,
+    timestamp: SystemTime,
+}
+
+impl FormatContext<'_> {
+    /// The domain the record is printed to.
+    pub fn domain(&self) -> LogDomain {
+        self.domain
+    }
+
+    /// The tag the record is printed with.
+    pub fn tag(&self) -> &CStr {
+        self.tag
+    }
+
+    /// When the record was logged, as returned by [`Builder::timestamp_source`].
+    ///
+    /// [`Builder::timestamp_source`]: crate::Builder::timestamp_source
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+}
+
+/// A format using the [`FormatContext`], together with its timestamp source.
+pub(crate) struct ContextFormat {
+    pub(crate) format: HilogContextFormatFn,
+    pub(crate) timestamp_source: TimestampSourceFn,
+}
+
+impl ContextFormat {
+    pub(crate) fn write(
+        &self,
+        buf: &mut HilogFormatter,
+        record: &Record<'_>,
+        domain: LogDomain,
+        tag: &CStr,
+    ) -> io::Result<()> {
+        let ctx = FormatContext {
+            domain,
+            tag,
+            timestamp: timestamp::now(&*self.timestamp_source),
+        };
+        (self.format)(buf, record, &ctx)
+    }
+}
 
 pub struct HilogFormatter {
-    buf: Rc<RefCell<Buffer>>,
+    buf: Buffer,
     // writer_style is not used for Hilog
 }
 impl HilogFormatter {
     pub(crate) fn new(writer: &HilogWriter) -> Self {
         HilogFormatter {
-            buf: Rc::new(RefCell::new(writer.buffer())),
+            buf: writer.buffer(),
         }
     }
-    pub(crate) fn print(&self, writer: &HilogWriter, level: LogLevel, domain: LogDomain, tag: &CStr) -> io::Result<()> {
-        writer.print(&self.buf.borrow(), level, domain, tag)
+    pub(crate) fn print(
+        &self,
+        writer: &HilogWriter,
+        log_type: LogType,
+        level: LogLevel,
+        domain: LogDomain,
+        tag: &CStr,
+        private: bool,
+    ) -> io::Result<()> {
+        writer.print(&self.buf, log_type, level, domain, tag, private)
+    }
+
+    /// Returns the bytes formatted so far.
+    #[cfg(any(feature = "backtrace", test))]
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        self.buf.as_bytes()
+    }
+
+    /// Takes the formatted bytes out of the buffer, leaving it empty.
+    pub(crate) fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf.bytes)
+    }
+
+    /// Reserves capacity for at least `additional` more bytes.
+    pub(crate) fn reserve(&mut self, additional: usize) {
+        self.buf.bytes.reserve(additional);
+    }
+
+    /// Releases memory of the buffer beyond `capacity` bytes.
+    pub(crate) fn shrink_to(&mut self, capacity: usize) {
+        self.buf.bytes.shrink_to(capacity);
     }
 
     pub(crate) fn clear(&mut self) {
-        self.buf.borrow_mut().clear();
+        self.buf.clear();
+    }
+
+    /// Returns the number of bytes formatted so far.
+    pub(crate) fn len(&self) -> usize {
+        self.buf.bytes.len()
+    }
+
+    /// Discards everything formatted after the first `len` bytes.
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.buf.bytes.truncate(len);
+        self.buf.truncated = false;
+    }
+
+    /// Returns how many more bytes fit within the size limit of the message.
+    pub(crate) fn remaining(&self) -> usize {
+        self.buf.remaining()
+    }
+
+    /// Prepares a formatter created for another writer to be used with `writer`.
+    pub(crate) fn reset(&mut self, writer: &HilogWriter) {
+        self.buf.clear();
+        self.buf.limit = writer.max_formatted_len();
     }
 }
 
 impl Write for HilogFormatter {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.buf.borrow_mut().write(buf)
+        self.buf.write(buf)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.buf.borrow_mut().flush()
+        self.buf.flush()
+    }
+}
+
+/// Writes to a formatter regardless of its size limit.
+///
+/// Used by structured formats for the parts that keep the output well-formed.
+pub(crate) struct Unlimited<'a>(pub(crate) &'a mut HilogFormatter);
+
+impl Write for Unlimited<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.buf.bytes.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
 }
 
 impl fmt::Debug for HilogFormatter {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let buf = self.buf.borrow();
+        let buf = &self.buf;
         f.debug_struct("Formatter")
             .field("buf", &buf)
             .finish()
@@ -78,15 +250,51 @@ impl fmt::Debug for HilogFormatter {
 }
 
 #[derive(Debug, Default)]
-pub(crate) struct Buffer(Vec<u8>);
+pub(crate) struct Buffer {
+    bytes: Vec<u8>,
+    // Bytes written beyond the limit are discarded.
+    limit: Option<usize>,
+    truncated: bool,
+}
 
 impl Buffer {
+    pub(crate) fn new(limit: Option<usize>) -> Self {
+        Buffer {
+            bytes: Vec::new(),
+            limit,
+            truncated: false,
+        }
+    }
+
     pub(crate) fn clear(&mut self) {
-        self.0.clear();
+        self.bytes.clear();
+        self.truncated = false;
+    }
+
+    pub(crate) fn remaining(&self) -> usize {
+        match self.limit {
+            // Once a part was discarded, later writes must not fill up the rest.
+            _ if self.truncated => 0,
+            Some(limit) => limit.saturating_sub(self.bytes.len()),
+            None => usize::MAX,
+        }
     }
 
     pub(crate) fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.0.extend(buf);
+        let remaining = self.remaining();
+        if buf.len() <= remaining {
+            self.bytes.extend(buf);
+        } else {
+            let mut end = remaining;
+            // Don't cut a UTF-8 character in half, continuation bytes start with 0b10.
+            while end > 0 && buf[end] & 0xc0 == 0x80 {
+                end -= 1;
+            }
+            self.bytes.extend(&buf[..end]);
+            self.truncated = true;
+        }
+        // Claim everything was written, so that formatting goes on as if nothing
+        // was truncated.
         Ok(buf.len())
     }
 
@@ -95,6 +303,6 @@ impl Buffer {
     }
 
     pub(crate) fn as_bytes(&self) -> &[u8] {
-        &self.0
+        &self.bytes
     }
 }
\ No newline at end of file
@@ -0,0 +1,219 @@
+//! A newline-delimited JSON format with a fixed schema.
+
+use std::io::{self, Write};
+use std::mem;
+use std::time::SystemTime;
+use log::Record;
+use crate::context;
+use crate::ohfmt::timestamp::Timestamp;
+use crate::ohfmt::{HilogFormatter, TimestampPrecision, Unlimited};
+
+/// Writes `record` as a single line JSON object.
+///
+/// The object contains the keys `ts` (RFC3339 with millisecond precision),
+/// `lvl`, `tag` (the module path, or the target if there is none), `msg` and
+/// `kv`, in this order. `kv` is an object with `default_kv` and the context of
+/// the thread, which overrides defaults with the same key. Newlines and other
+/// control characters in strings are escaped, so the object never spans
+/// several lines.
+///
+/// Only the message is cut to stay within the size limit of the formatter, so
+/// that the object is always complete. If everything but the message exceeds
+/// the limit, the object is written in full anyway.
+pub(crate) fn write(
+    buf: &mut HilogFormatter,
+    record: &Record<'_>,
+    now: SystemTime,
+    default_kv: &[(String, String)],
+) -> io::Result<()> {
+    let tag = record
+        .module_path()
+        .unwrap_or_else(|| crate::strip_target_markers(record.target()));
+
+    let mut head = Unlimited(buf);
+    write!(
+        head,
+        "{{\"ts\":\"{}\",\"lvl\":\"{}\",\"tag\":\"",
+        Timestamp::new(now, TimestampPrecision::Millis),
+        record.level()
+    )?;
+    write!(JsonEscape::new(&mut head), "{}", tag)?;
+    head.write_all(b"\",\"msg\":\"")?;
+
+    // The tail is measured first to know how much of the message fits.
+    let mut tail_len = ByteCount(0);
+    write_tail(&mut tail_len, default_kv)?;
+    let budget = buf.remaining().saturating_sub(tail_len.0);
+    write!(JsonEscape::bounded(&mut Unlimited(buf), budget), "{}", record.args())?;
+    write_tail(&mut Unlimited(buf), default_kv)
+}
+
+/// Writes everything after the message: the end of its string, `kv` and the end
+/// of the object.
+fn write_tail<W: Write>(out: &mut W, default_kv: &[(String, String)]) -> io::Result<()> {
+    out.write_all(b"\",\"kv\":{")?;
+    let mut first = true;
+    context::for_each_with_defaults(default_kv, |key, value| {
+        if !mem::take(&mut first) {
+            out.write_all(b",")?;
+        }
+        out.write_all(b"\"")?;
+        write!(JsonEscape::new(&mut *out), "{}", key)?;
+        out.write_all(b"\":\"")?;
+        write!(JsonEscape::new(&mut *out), "{}", value)?;
+        out.write_all(b"\"")
+    })?;
+    out.write_all(b"}}")
+}
+
+/// Counts the bytes written to it.
+struct ByteCount(usize);
+
+impl Write for ByteCount {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Escapes everything written to it for use in a JSON string.
+///
+/// Once `budget` bytes were written, the rest is discarded, without cutting an
+/// escape sequence or a UTF-8 character in half.
+struct JsonEscape<W> {
+    out: W,
+    budget: usize,
+}
+
+impl<W: Write> JsonEscape<W> {
+    fn new(out: W) -> Self {
+        JsonEscape::bounded(out, usize::MAX)
+    }
+
+    fn bounded(out: W, budget: usize) -> Self {
+        JsonEscape { out, budget }
+    }
+
+    /// Writes `bytes` within the budget, cutting them at a character boundary
+    /// only if `splittable`.
+    fn write_part(&mut self, bytes: &[u8], splittable: bool) -> io::Result<()> {
+        if bytes.len() <= self.budget {
+            self.budget -= bytes.len();
+            return self.out.write_all(bytes);
+        }
+
+        let mut end = if splittable { self.budget } else { 0 };
+        // Continuation bytes of UTF-8 start with 0b10.
+        while end > 0 && bytes[end] & 0xc0 == 0x80 {
+            end -= 1;
+        }
+        // Nothing more is written once the budget is exceeded.
+        self.budget = 0;
+        self.out.write_all(&bytes[..end])
+    }
+}
+
+impl<W: Write> Write for JsonEscape<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        const HEX: &[u8; 16] = b"0123456789abcdef";
+
+        let mut start = 0;
+        for (i, &b) in buf.iter().enumerate() {
+            let unicode;
+            let escaped: &[u8] = match b {
+                b'"' => b"\\\"",
+                b'\\' => b"\\\\",
+                b'\n' => b"\\n",
+                b'\r' => b"\\r",
+                b'\t' => b"\\t",
+                0..=0x1f => {
+                    unicode = [b'\\', b'u', b'0', b'0', HEX[usize::from(b >> 4)], HEX[usize::from(b & 0xf)]];
+                    &unicode
+                }
+                _ => continue,
+            };
+            self.write_part(&buf[start..i], true)?;
+            self.write_part(escaped, false)?;
+            start = i + 1;
+        }
+        self.write_part(&buf[start..], true)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+    use log::Level;
+    use crate::ohfmt::writer::{HilogWriter, DEFAULT_MAX_FORMATTED_LEN};
+    use super::*;
+
+    #[test]
+    fn escapes_quotes_backslashes_and_control_characters() {
+        let writer = HilogWriter::default();
+        let mut buf = HilogFormatter::new(&writer);
+
+        write!(JsonEscape::new(&mut buf), "a\nb\"c\\d\u{1}e\r\t").unwrap();
+
+        assert_eq!(buf.as_bytes(), br#"a\nb\"c\\d\u0001e\r\t"#);
+    }
+
+    #[test]
+    fn multiline_message_stays_on_one_line() {
+        let writer = HilogWriter::default();
+        let mut buf = HilogFormatter::new(&writer);
+        let record = Record::builder()
+            .level(Level::Warn)
+            .target("my_app")
+            .args(format_args!("line 1\nline 2"))
+            .build();
+
+        write(&mut buf, &record, UNIX_EPOCH, &[("service".to_owned(), "auth".to_owned())]).unwrap();
+
+        assert_eq!(
+            buf.as_bytes(),
+            br#"{"ts":"1970-01-01T00:00:00.000Z","lvl":"WARN","tag":"my_app","msg":"line 1\nline 2","kv":{"service":"auth"}}"#
+        );
+        assert!(!buf.as_bytes().contains(&b'\n'));
+    }
+
+    #[test]
+    fn long_message_is_cut_within_the_object() {
+        let writer = HilogWriter::default();
+        let mut buf = HilogFormatter::new(&writer);
+        let message = "\"quoted\"\t".repeat(1000);
+        let kv = [("service".to_owned(), "auth".to_owned())];
+
+        write(
+            &mut buf,
+            &Record::builder().level(Level::Info).target("my_app").args(format_args!("{}", message)).build(),
+            UNIX_EPOCH,
+            &kv,
+        )
+        .unwrap();
+
+        let json = std::str::from_utf8(buf.as_bytes()).unwrap();
+        assert!(json.len() <= DEFAULT_MAX_FORMATTED_LEN, "{}", json.len());
+        let msg = json
+            .strip_prefix(r#"{"ts":"1970-01-01T00:00:00.000Z","lvl":"INFO","tag":"my_app","msg":""#)
+            .and_then(|rest| rest.strip_suffix(r#"","kv":{"service":"auth"}}"#))
+            .unwrap();
+        assert!(msg.len() > 3000);
+        // Every quote and backslash in the message is a complete escape.
+        let mut chars = msg.chars();
+        while let Some(c) = chars.next() {
+            assert_ne!(c, '"');
+            if c == '\\' {
+                assert!(matches!(chars.next(), Some('"' | 't')), "{}", msg);
+            }
+        }
+    }
+}
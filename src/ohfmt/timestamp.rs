@@ -0,0 +1,214 @@
+use crate::ohfmt::TimestampPrecision;
+use std::cell::Cell;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECS_PER_DAY: u64 = 86_400;
+
+thread_local! {
+    /// The timestamp of the record being logged with `Logger::log_at` on this thread.
+    static OVERRIDE: Cell<Option<SystemTime>> = const { Cell::new(None) };
+}
+
+/// Calls `f` with `time` returned by [`now`] instead of the timestamp source.
+pub(crate) fn with_override<F, R>(time: SystemTime, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    struct Restore(Option<SystemTime>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let _ = OVERRIDE.try_with(|o| o.set(self.0));
+        }
+    }
+
+    let _restore = Restore(OVERRIDE.try_with(|o| o.replace(Some(time))).ok().flatten());
+    f()
+}
+
+/// Returns the timestamp of the current record, from `source` unless overridden.
+pub(crate) fn now(source: &dyn Fn() -> SystemTime) -> SystemTime {
+    OVERRIDE
+        .try_with(Cell::get)
+        .ok()
+        .flatten()
+        .unwrap_or_else(source)
+}
+
+/// An RFC3339 formatted UTC timestamp.
+///
+/// Times before the unix epoch are written as such, e.g. `1969-12-31T23:59:59Z`.
+pub(crate) struct Timestamp {
+    time: SystemTime,
+    precision: TimestampPrecision,
+    with_date: bool,
+    human: bool,
+}
+
+impl Timestamp {
+    pub(crate) fn new(time: SystemTime, precision: TimestampPrecision) -> Self {
+        Timestamp {
+            time,
+            precision,
+            with_date: true,
+            human: false,
+        }
+    }
+
+    /// Only writes the time of day, e.g. `12:34:56Z`.
+    pub(crate) fn time_only(mut self) -> Self {
+        self.with_date = false;
+        self
+    }
+
+    /// Writes the abbreviated weekday and the time of day in UTC, e.g. `Mon 14:03:22`.
+    ///
+    /// The precision is ignored, there are no fractional seconds.
+    pub(crate) fn human(mut self) -> Self {
+        self.human = true;
+        self
+    }
+
+    /// Returns the days since the unix epoch.
+    pub(crate) fn day(&self) -> u64 {
+        self.time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / SECS_PER_DAY
+    }
+
+    /// Returns the seconds since the unix epoch, negative before it, and the nanoseconds.
+    fn secs_and_nanos(&self) -> (i64, u32) {
+        match self.time.duration_since(UNIX_EPOCH) {
+            Ok(since) => (
+                i64::try_from(since.as_secs()).unwrap_or(i64::MAX),
+                since.subsec_nanos(),
+            ),
+            Err(e) => {
+                let before = e.duration();
+                let secs = -i64::try_from(before.as_secs()).unwrap_or(i64::MAX);
+                match before.subsec_nanos() {
+                    0 => (secs, 0),
+                    // Round down to the previous second, the nanoseconds count up from it.
+                    nanos => (secs - 1, 1_000_000_000 - nanos),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (secs, nanos) = self.secs_and_nanos();
+        let days = secs.div_euclid(SECS_PER_DAY as i64);
+        let secs_of_day = secs.rem_euclid(SECS_PER_DAY as i64);
+
+        if self.human {
+            return write!(
+                f,
+                "{} {:02}:{:02}:{:02}",
+                weekday_from_days(days),
+                secs_of_day / 3600,
+                secs_of_day / 60 % 60,
+                secs_of_day % 60,
+            );
+        }
+
+        if self.with_date {
+            let (year, month, day) = civil_from_days(days);
+            write!(f, "{:04}-{:02}-{:02}T", year, month, day)?;
+        }
+
+        write!(
+            f,
+            "{:02}:{:02}:{:02}",
+            secs_of_day / 3600,
+            secs_of_day / 60 % 60,
+            secs_of_day % 60,
+        )?;
+
+        match self.precision {
+            TimestampPrecision::Seconds => {}
+            TimestampPrecision::Millis => write!(f, ".{:03}", nanos / 1_000_000)?,
+            TimestampPrecision::Micros => write!(f, ".{:06}", nanos / 1_000)?,
+            TimestampPrecision::Nanos => write!(f, ".{:09}", nanos)?,
+        }
+
+        f.write_str("Z")
+    }
+}
+
+/// Returns the abbreviated name of the weekday, days after the unix epoch.
+fn weekday_from_days(days: i64) -> &'static str {
+    // 1970-01-01 was a Thursday.
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    WEEKDAYS[days.rem_euclid(7) as usize]
+}
+
+/// Converts days since the unix epoch into a (year, month, day) civil date.
+///
+/// See <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // Shift the epoch to 0000-03-01, so that leap days are at the end of a year.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month as u32, day as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn weekday_around_epoch() {
+        assert_eq!(weekday_from_days(-1), "Wed");
+        assert_eq!(weekday_from_days(0), "Thu");
+        assert_eq!(weekday_from_days(1), "Fri");
+        // 2024-02-29
+        assert_eq!(weekday_from_days(19_782), "Thu");
+    }
+
+    #[test]
+    fn civil_date_around_epoch() {
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(1), (1970, 1, 2));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(19_783), (2024, 3, 1));
+    }
+
+    #[test]
+    fn human_timestamp_around_epoch() {
+        let human = |time| {
+            Timestamp::new(time, TimestampPrecision::Seconds)
+                .human()
+                .to_string()
+        };
+
+        assert_eq!(human(UNIX_EPOCH - Duration::from_secs(1)), "Wed 23:59:59");
+        assert_eq!(human(UNIX_EPOCH), "Thu 00:00:00");
+        assert_eq!(
+            human(UNIX_EPOCH + Duration::from_secs(SECS_PER_DAY + 3723)),
+            "Fri 01:02:03"
+        );
+    }
+
+    #[test]
+    fn rfc3339_before_epoch() {
+        let time = UNIX_EPOCH - Duration::from_millis(1);
+        let timestamp = Timestamp::new(time, TimestampPrecision::Millis).to_string();
+
+        assert_eq!(timestamp, "1969-12-31T23:59:59.999Z");
+    }
+}
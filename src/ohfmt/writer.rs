@@ -1,32 +1,545 @@
+use std::borrow::Cow;
 use std::ffi::{CStr, CString};
-use std::io;
-use hilog_sys::LogLevel;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::RwLock;
+use std::{fmt, io, mem, thread};
+use crate::sys::{self, LogLevel, LogType};
 use crate::{hilog_log, LogDomain};
 use crate::ohfmt::Buffer;
+use crate::sink::{Sink, SinkRecord};
+use crate::stats::Counters;
 
-#[derive(Debug, Default)]
-pub struct HilogWriter;
+pub(crate) type OnEmitFn = Box<dyn Fn(&mut Vec<u8>) + Sync + Send>;
+
+/// Number of consecutive hilog failures after which output goes to stderr.
+const MAX_CONSECUTIVE_FAILURES: usize = 16;
+
+/// Number of times a failed print is retried in [`WriteMode::Blocking`].
+const MAX_RETRIES: usize = 3;
+
+/// What hilog prints instead of a private argument, outside of debug mode.
+const REDACTED: &CStr = c"<private>";
+
+/// Default upper bound of a formatted message, hilog truncates longer messages anyway.
+pub(crate) const DEFAULT_MAX_FORMATTED_LEN: usize = 4096;
+
+/// How hard the writer tries to hand a message to hilog.
+#[allow(clippy::exhaustive_enums)] // compatibility
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum WriteMode {
+    /// Call hilog once and drop the message if it fails.
+    #[default]
+    BestEffort,
+    /// Retry a failed call a few times before the message is dropped.
+    Blocking,
+}
+
+pub struct HilogWriter {
+    log_type: LogType,
+    write_mode: WriteMode,
+    on_emit: Option<OnEmitFn>,
+    sink: Option<Box<dyn Sink>>,
+    sanitize: SanitizeOptions,
+    max_formatted_len: Option<usize>,
+    consecutive_failures: AtomicUsize,
+    // Set once hilog failed persistently, after which all output goes to stderr.
+    degraded: AtomicBool,
+    counters: Counters,
+    // Held for reading by every write and for writing while a group is printed,
+    // so that no other write ends up in between the messages of a group.
+    write_lock: RwLock<()>,
+}
+
+/// A formatted message of a [`LogGroup`](crate::LogGroup), waiting to be printed.
+pub(crate) struct GroupEntry {
+    pub(crate) msg: Vec<u8>,
+    pub(crate) log_type: LogType,
+    pub(crate) level: LogLevel,
+    pub(crate) domain: LogDomain,
+    pub(crate) tag: CString,
+    pub(crate) private: bool,
+    // Whether this is a copy of the previous entry for another domain.
+    pub(crate) mirror: bool,
+}
 
 impl HilogWriter {
     pub(super) fn buffer(&self) -> Buffer {
-        Buffer(Vec::new())
+        Buffer::new(self.max_formatted_len)
+    }
+
+    /// Returns the upper bound of a formatted message.
+    pub(super) fn max_formatted_len(&self) -> Option<usize> {
+        self.max_formatted_len
+    }
+
+    /// Returns the counters of the records printed with this writer.
+    pub(crate) fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    /// Returns the configured log type.
+    pub(crate) fn log_type(&self) -> LogType {
+        self.log_type
+    }
+
+    /// Prints the buffer to hilog with the given log type.
+    ///
+    /// Returns an error if hilog rejected the message, e.g. because the process
+    /// lacks the privileges for the log type.
+    pub(super) fn print(
+        &self,
+        buf: &Buffer,
+        log_type: LogType,
+        level: LogLevel,
+        domain: LogDomain,
+        tag: &CStr,
+        private: bool,
+    ) -> io::Result<()> {
+        self.print_vec(buf.as_bytes().to_vec(), log_type, level, domain, tag, private)
+    }
+
+    /// Prints an already formatted C string to hilog.
+    ///
+    /// The message is only copied if it needs to be passed to the `on_emit` callback
+    /// or has to be changed by [`sanitize_message`], e.g. because it is longer
+    /// than the maximum size of a formatted message.
+    pub(crate) fn print_cstr(&self, msg: &CStr, level: LogLevel, domain: LogDomain, tag: &CStr) -> io::Result<()> {
+        self.print_cstr_as(msg, false, self.log_type, level, domain, tag)
+    }
+
+    /// Prints an already formatted C string to hilog with the given log type.
+    ///
+    /// A message the caller already knows to be valid UTF-8 isn't validated again.
+    pub(crate) fn print_cstr_as(
+        &self,
+        msg: &CStr,
+        is_utf8: bool,
+        log_type: LogType,
+        level: LogLevel,
+        domain: LogDomain,
+        tag: &CStr,
+    ) -> io::Result<()> {
+        let opts = SanitizeOptions {
+            max_len: match (self.sanitize.max_len, self.max_formatted_len) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            },
+            ..self.sanitize
+        };
+        let sanitized = sanitize(msg.to_bytes(), &opts, is_utf8);
+        if self.on_emit.is_some() {
+            return self.print_vec(sanitized.into_owned(), log_type, level, domain, tag, false);
+        }
+        let copied;
+        let msg = match sanitized {
+            Cow::Borrowed(bytes) if bytes.len() == msg.to_bytes().len() => msg,
+            sanitized => {
+                // SAFETY: `sanitize` escapes all NUL bytes.
+                copied = unsafe { CString::from_vec_unchecked(sanitized.into_owned()) };
+                &copied
+            }
+        };
+        let _lock = self.write_lock.read().unwrap_or_else(|e| e.into_inner());
+        self.emit(msg, log_type, level, domain, tag, false)
     }
-    
-    pub(super) fn print(&self, buf: &Buffer, level: LogLevel, domain: LogDomain, tag: &CStr) -> io::Result<()> {
-        let c_msg = unsafe { CString::from_vec_unchecked(buf.as_bytes().to_vec()) };
-        hilog_log(hilog_sys::LogType::LOG_APP, level,domain, tag, c_msg.as_ref());
+
+    /// Prints the messages of a group back to back, without other writes in between.
+    ///
+    /// The `on_emit` callback runs for all messages before the write lock is
+    /// taken, so that it may log itself without deadlocking.
+    pub(crate) fn print_group(&self, entries: Vec<GroupEntry>) {
+        let prepared: Vec<(CString, GroupEntry)> = entries
+            .into_iter()
+            .map(|mut entry| (self.prepare(mem::take(&mut entry.msg)), entry))
+            .collect();
+
+        let _lock = self.write_lock.write().unwrap_or_else(|e| e.into_inner());
+        for (msg, entry) in prepared {
+            let res = self.emit(&msg, entry.log_type, entry.level, entry.domain, &entry.tag, entry.private);
+            if !entry.mirror {
+                self.counters.record(res);
+            }
+        }
+    }
+
+    fn print_vec(
+        &self,
+        msg: Vec<u8>,
+        log_type: LogType,
+        level: LogLevel,
+        domain: LogDomain,
+        tag: &CStr,
+        private: bool,
+    ) -> io::Result<()> {
+        let c_msg = self.prepare(msg);
+        let _lock = self.write_lock.read().unwrap_or_else(|e| e.into_inner());
+        self.emit(c_msg.as_ref(), log_type, level, domain, tag, private)
+    }
+
+    /// Runs the `on_emit` callback and [`sanitize_message`] on a message.
+    fn prepare(&self, mut msg: Vec<u8>) -> CString {
+        if let Some(on_emit) = &self.on_emit {
+            on_emit(&mut msg);
+        }
+        if let Cow::Owned(sanitized) = sanitize_message(&msg, &self.sanitize) {
+            msg = sanitized;
+        }
+        // SAFETY: `sanitize_message` escapes all NUL bytes.
+        unsafe { CString::from_vec_unchecked(msg) }
+    }
+
+    /// Hands the message to hilog, falling back to stderr if hilog keeps failing.
+    ///
+    /// The caller must hold the write lock.
+    fn emit(
+        &self,
+        msg: &CStr,
+        log_type: LogType,
+        level: LogLevel,
+        domain: LogDomain,
+        tag: &CStr,
+        private: bool,
+    ) -> io::Result<()> {
+        let res = self.emit_inner(msg, log_type, level, domain, tag, private);
+        if res.is_ok() {
+            self.counters.written(msg.to_bytes().len());
+        }
+        res
+    }
+
+    fn emit_inner(
+        &self,
+        msg: &CStr,
+        log_type: LogType,
+        level: LogLevel,
+        domain: LogDomain,
+        tag: &CStr,
+        private: bool,
+    ) -> io::Result<()> {
+        #[cfg(feature = "test-util")]
+        if let Some(test_sink) = crate::sink::test_sink() {
+            let msg = if private { REDACTED } else { msg };
+            test_sink(level, domain, tag, msg.to_bytes());
+            return Ok(());
+        }
+        if let Some(sink) = &self.sink {
+            let record = SinkRecord { log_type, level, domain, tag, private };
+            return sink.write(&record, msg.to_bytes());
+        }
+        if self.degraded.load(Ordering::Relaxed) {
+            return print_stderr(msg, level, tag, private);
+        }
+
+        // hilog renders escape sequences literally, so colors are never passed on.
+        let stripped;
+        let msg = match strip_ansi(msg.to_bytes()) {
+            Cow::Borrowed(_) => msg,
+            Cow::Owned(plain) => {
+                // SAFETY: `msg` contains no NUL bytes and stripping adds none.
+                stripped = unsafe { CString::from_vec_unchecked(plain) };
+                &stripped
+            }
+        };
+
+        let mut res = hilog_log(log_type, level, domain, tag, msg, private);
+        if self.write_mode == WriteMode::Blocking {
+            for _ in 0..MAX_RETRIES {
+                if res.is_ok() {
+                    break;
+                }
+                // Failures are usually transient, e.g. when hilogd is busy.
+                thread::yield_now();
+                res = hilog_log(log_type, level, domain, tag, msg, private);
+            }
+        }
+
+        match res {
+            Ok(()) => {
+                self.consecutive_failures.store(0, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(e) => {
+                let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+                if failures >= MAX_CONSECUTIVE_FAILURES && !self.degraded.swap(true, Ordering::Relaxed) {
+                    eprintln!(
+                        "hilog: OH_LOG_Print failed {} times in a row ({}), writing logs to stderr from now on",
+                        failures, e
+                    );
+                    return print_stderr(msg, level, tag, private);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Flushes the sink, or stderr once hilog failed persistently.
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        if let Some(sink) = &self.sink {
+            return sink.flush();
+        }
+        if self.degraded.load(Ordering::Relaxed) {
+            return io::stderr().flush();
+        }
         Ok(())
     }
 }
 
-#[derive(Default)]
+/// Options for [`sanitize_message`].
+#[non_exhaustive]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SanitizeOptions {
+    /// Replace invalid UTF-8 with `U+FFFD REPLACEMENT CHARACTER`.
+    pub validate_utf8: bool,
+    /// Truncate messages longer than this many bytes.
+    pub max_len: Option<usize>,
+}
+
+impl Default for SanitizeOptions {
+    fn default() -> Self {
+        SanitizeOptions {
+            validate_utf8: true,
+            max_len: None,
+        }
+    }
+}
+
+/// Makes a formatted message safe to be passed to hilog as a C string.
+///
+/// NUL bytes are escaped as `\0`, so that they don't cut off the message. With
+/// [`SanitizeOptions::validate_utf8`], invalid UTF-8 is replaced, and with
+/// [`SanitizeOptions::max_len`] the message is truncated, at a character boundary
+/// if it is valid UTF-8. The input is only copied if it has to be changed.
+///
+/// This is applied to every message right before it is printed.
+///
+/// # Examples
+///
+/// ```
+/// use hilog::{sanitize_message, SanitizeOptions};
+///
+/// let sanitized = sanitize_message(b"a\0b\xff", &SanitizeOptions::default());
+/// assert_eq!(&*sanitized, "a\\0b\u{FFFD}".as_bytes());
+/// ```
+pub fn sanitize_message<'a>(msg: &'a [u8], opts: &SanitizeOptions) -> Cow<'a, [u8]> {
+    sanitize(msg, opts, false)
+}
+
+/// Implements [`sanitize_message`], skipping the validation if `msg` is known to
+/// be valid UTF-8.
+fn sanitize<'a>(msg: &'a [u8], opts: &SanitizeOptions, mut is_utf8: bool) -> Cow<'a, [u8]> {
+    let mut msg = Cow::Borrowed(msg);
+
+    if msg.contains(&0) {
+        let mut escaped = Vec::with_capacity(msg.len() + 8);
+        for &b in msg.iter() {
+            match b {
+                0 => escaped.extend_from_slice(b"\\0"),
+                b => escaped.push(b),
+            }
+        }
+        msg = Cow::Owned(escaped);
+    }
+
+    if opts.validate_utf8 && !is_utf8 {
+        let replaced = match String::from_utf8_lossy(&msg) {
+            Cow::Owned(valid) => Some(valid.into_bytes()),
+            Cow::Borrowed(_) => None,
+        };
+        if let Some(replaced) = replaced {
+            msg = Cow::Owned(replaced);
+        }
+        is_utf8 = true;
+    }
+
+    if let Some(max_len) = opts.max_len.filter(|&max_len| msg.len() > max_len) {
+        let mut end = max_len;
+        // Continuation bytes of UTF-8 start with 0b10.
+        while is_utf8 && end > 0 && msg[end] & 0xc0 == 0x80 {
+            end -= 1;
+        }
+        msg = match msg {
+            Cow::Borrowed(msg) => Cow::Borrowed(&msg[..end]),
+            Cow::Owned(mut msg) => {
+                msg.truncate(end);
+                Cow::Owned(msg)
+            }
+        };
+    }
+
+    msg
+}
+
+/// Removes ANSI escape sequences, such as colors, from a message.
+///
+/// Control sequences (`ESC [` up to the final byte) and other two byte escapes
+/// are removed. The message is only copied if it contains an escape.
+fn strip_ansi(msg: &[u8]) -> Cow<'_, [u8]> {
+    const ESC: u8 = 0x1b;
+
+    if !msg.contains(&ESC) {
+        return Cow::Borrowed(msg);
+    }
+
+    let mut plain = Vec::with_capacity(msg.len());
+    let mut bytes = msg.iter().copied();
+    while let Some(b) = bytes.next() {
+        if b != ESC {
+            plain.push(b);
+            continue;
+        }
+        // Other escapes are two bytes long. In a control sequence, parameter and
+        // intermediate bytes are followed by a final byte in `@..=~`.
+        if bytes.next() == Some(b'[') {
+            for b in bytes.by_ref() {
+                if (0x40..=0x7e).contains(&b) {
+                    break;
+                }
+            }
+        }
+    }
+    Cow::Owned(plain)
+}
+
+/// Prints a message to stderr, in a layout similar to hilog.
+///
+/// Private messages are redacted, like hilog does outside of debug mode.
+fn print_stderr(msg: &CStr, level: LogLevel, tag: &CStr, private: bool) -> io::Result<()> {
+    let msg = if private { REDACTED } else { msg };
+    let level = match level {
+        LogLevel::LOG_DEBUG => 'D',
+        LogLevel::LOG_INFO => 'I',
+        LogLevel::LOG_WARN => 'W',
+        LogLevel::LOG_ERROR => 'E',
+        LogLevel::LOG_FATAL => 'F',
+        _ => '?',
+    };
+    let mut stderr = io::stderr().lock();
+    write!(stderr, "{} {}: ", level, tag.to_string_lossy())?;
+    stderr.write_all(msg.to_bytes())?;
+    if !msg.to_bytes().ends_with(b"\n") {
+        stderr.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+impl Default for HilogWriter {
+    fn default() -> Self {
+        Builder::default().build()
+    }
+}
+
+impl fmt::Debug for HilogWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HilogWriter")
+            .field("log_type", &self.log_type)
+            .field("on_emit", &self.on_emit.is_some())
+            .field("sink", &self.sink.is_some())
+            .field("sanitize", &self.sanitize)
+            .field("degraded", &self.degraded.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
 pub struct Builder {
+    pub(crate) log_type: LogType,
+    pub(crate) write_mode: WriteMode,
+    pub(crate) on_emit: Option<OnEmitFn>,
+    pub(crate) sink: Option<Box<dyn Sink>>,
+    pub(crate) sanitize: SanitizeOptions,
+    pub(crate) max_formatted_len: Option<usize>,
     built: bool,
 }
 
 impl Builder {
     pub(crate) fn build(&mut self) -> HilogWriter {
         self.built = true;
-        HilogWriter
+        HilogWriter {
+            log_type: self.log_type,
+            write_mode: self.write_mode,
+            on_emit: self.on_emit.take(),
+            sink: self.sink.take(),
+            sanitize: self.sanitize,
+            max_formatted_len: self.max_formatted_len,
+            consecutive_failures: AtomicUsize::new(0),
+            // Without hilog, e.g. on the development host, everything goes to stderr.
+            degraded: AtomicBool::new(!sys::HAS_HILOG),
+            counters: Counters::default(),
+            write_lock: RwLock::new(()),
+        }
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Builder {
+            log_type: LogType::LOG_APP,
+            write_mode: WriteMode::default(),
+            on_emit: None,
+            sink: None,
+            sanitize: SanitizeOptions::default(),
+            max_formatted_len: Some(DEFAULT_MAX_FORMATTED_LEN),
+            built: false,
+        }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use crate::LOG_KMSG;
+    use super::*;
+
+    /// Records the log type of every message written to it.
+    struct LogTypes(Arc<Mutex<Vec<LogType>>>);
+
+    impl Sink for LogTypes {
+        fn write(&self, record: &SinkRecord<'_>, _msg: &[u8]) -> io::Result<()> {
+            self.0.lock().unwrap_or_else(|e| e.into_inner()).push(record.log_type());
+            Ok(())
+        }
+    }
+
+    fn buffer(writer: &HilogWriter, msg: &str) -> Buffer {
+        let mut buf = writer.buffer();
+        buf.write(msg.as_bytes()).unwrap();
+        buf
+    }
+
+    #[test]
+    fn kmsg_log_type_is_passed_on() {
+        let log_types = Arc::new(Mutex::new(Vec::new()));
+        let writer = Builder {
+            log_type: LOG_KMSG,
+            sink: Some(Box::new(LogTypes(Arc::clone(&log_types)))),
+            ..Default::default()
+        }
+        .build();
+
+        let buf = buffer(&writer, "audit");
+        writer.print(&buf, writer.log_type(), LogLevel::LOG_INFO, LogDomain::new(0), c"kmsg", false).unwrap();
+        writer.print_cstr(c"audit", LogLevel::LOG_INFO, LogDomain::new(0), c"kmsg").unwrap();
+
+        assert_eq!(*log_types.lock().unwrap(), [LOG_KMSG, LOG_KMSG]);
+    }
+
+    // Relies on the stand-in for `OH_LOG_Print`, which always fails.
+    #[cfg(not(target_env = "ohos"))]
+    #[test]
+    fn failed_kmsg_write_is_reported() {
+        let writer = Builder {
+            log_type: LOG_KMSG,
+            ..Default::default()
+        }
+        .build();
+        // Pretend hilog is available, so that its failure is seen by the writer.
+        writer.degraded.store(false, Ordering::Relaxed);
+
+        let buf = buffer(&writer, "audit");
+        let res = writer.print(&buf, LOG_KMSG, LogLevel::LOG_INFO, LogDomain::new(0), c"kmsg", false);
+
+        // Counting the record as dropped is up to the logger.
+        assert!(res.is_err());
+        assert_eq!(writer.counters().snapshot().bytes_written, 0);
+    }
+}
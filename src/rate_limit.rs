@@ -0,0 +1,58 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// A global budget of records per fixed time window.
+pub(crate) struct RateLimit {
+    max_records: u64,
+    per: Duration,
+    start: Instant,
+    window: AtomicU64,
+    used: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// The outcome of [`RateLimit::acquire`].
+pub(crate) struct Admission {
+    /// Whether the record may be logged.
+    pub(crate) allowed: bool,
+    /// Records dropped in the previous window, reported once when a new window starts.
+    pub(crate) dropped_before: u64,
+}
+
+impl RateLimit {
+    pub(crate) fn new(max_records: u64, per: Duration) -> Self {
+        RateLimit {
+            max_records,
+            // A window of zero would never reset.
+            per: per.max(Duration::from_nanos(1)),
+            start: Instant::now(),
+            window: AtomicU64::new(0),
+            used: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Takes one record from the budget of the current window.
+    pub(crate) fn acquire(&self) -> Admission {
+        let window = (self.start.elapsed().as_nanos() / self.per.as_nanos()) as u64;
+        let current = self.window.load(Ordering::Relaxed);
+
+        let mut dropped_before = 0;
+        if window > current
+            && self
+                .window
+                .compare_exchange(current, window, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            // Records racing with the reset may be counted in either window.
+            self.used.store(0, Ordering::Relaxed);
+            dropped_before = self.dropped.swap(0, Ordering::Relaxed);
+        }
+
+        let allowed = self.used.fetch_add(1, Ordering::Relaxed) < self.max_records;
+        if !allowed {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        Admission { allowed, dropped_before }
+    }
+}
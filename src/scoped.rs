@@ -0,0 +1,98 @@
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::sync::Once;
+use log::{Log, Metadata, Record};
+use crate::Logger;
+
+thread_local! {
+    static SCOPED: RefCell<Vec<Logger>> = const { RefCell::new(Vec::new()) };
+}
+
+static INSTALL: Once = Once::new();
+
+/// The global logger, forwarding to the innermost scoped logger of the thread.
+struct Dispatch;
+
+impl Dispatch {
+    fn with_current<F, R>(f: F) -> Option<R>
+    where
+        F: FnOnce(&Logger) -> R,
+    {
+        SCOPED
+            .try_with(|scoped| scoped.try_borrow().ok().and_then(|scoped| scoped.last().map(f)))
+            .ok()
+            .flatten()
+    }
+}
+
+impl Log for Dispatch {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        Dispatch::with_current(|logger| logger.enabled(metadata)).unwrap_or(false)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        Dispatch::with_current(|logger| logger.log(record));
+    }
+
+    fn flush(&self) {
+        Dispatch::with_current(|logger| logger.flush());
+    }
+}
+
+/// Uses `logger` for the records of the current thread until the guard is dropped.
+///
+/// On first use, a global logger is installed that forwards every record to the
+/// innermost scoped logger of the thread it is logged on, and drops it if there
+/// is none. This allows tests to configure logging for their duration, even when
+/// they run in parallel. Records logged on other threads, e.g. ones spawned by
+/// the test, don't reach `logger`. If another global logger was installed
+/// before, it keeps receiving all records and `logger` is never used.
+///
+/// The maximum level of `log` is raised to the filter of `logger` if needed and
+/// is not lowered again.
+///
+/// # Examples
+///
+/// ```
+/// use hilog::Builder;
+/// use log::LevelFilter;
+///
+/// let _guard = hilog::scoped(Builder::new().filter_level(LevelFilter::Debug).build());
+/// log::debug!("printed with the scoped logger");
+/// ```
+pub fn scoped(logger: Logger) -> ScopedGuard {
+    INSTALL.call_once(|| {
+        static DISPATCH: Dispatch = Dispatch;
+        let _ = log::set_logger(&DISPATCH);
+    });
+    log::set_max_level(log::max_level().max(logger.filter()));
+
+    let depth = SCOPED
+        .try_with(|scoped| {
+            let mut scoped = scoped.borrow_mut();
+            scoped.push(logger);
+            scoped.len() - 1
+        })
+        .unwrap_or(0);
+
+    ScopedGuard {
+        depth,
+        _not_send: PhantomData,
+    }
+}
+
+/// Restores the previous logger of the thread when dropped.
+///
+/// Created by [`scoped`].
+#[must_use = "the logger is uninstalled when the guard is dropped"]
+pub struct ScopedGuard {
+    depth: usize,
+    // The scoped loggers are thread-local, so the guard must stay on its thread.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for ScopedGuard {
+    fn drop(&mut self) {
+        let _ = SCOPED.try_with(|scoped| scoped.borrow_mut().truncate(self.depth));
+    }
+}
@@ -0,0 +1,125 @@
+use std::ffi::CStr;
+use std::io;
+#[cfg(feature = "test-util")]
+use std::sync::RwLock;
+use crate::sys::{LogLevel, LogType};
+use crate::LogDomain;
+
+/// A destination for formatted records, used instead of hilog.
+///
+/// A sink receives the bytes produced by the format, after the `on_emit`
+/// callback ran, together with the metadata hilog would have been given. This
+/// allows to inspect, transform or forward messages, e.g. to capture them in
+/// tests on the development host. Set it with [`Builder::sink`](crate::Builder::sink).
+///
+/// # Examples
+///
+/// ```
+/// use std::io;
+/// use hilog::{Builder, Sink, SinkRecord};
+///
+/// struct Stdout;
+///
+/// impl Sink for Stdout {
+///     fn write(&self, record: &SinkRecord<'_>, msg: &[u8]) -> io::Result<()> {
+///         println!("{}: {}", record.tag().to_string_lossy(), String::from_utf8_lossy(msg));
+///         Ok(())
+///     }
+/// }
+///
+/// let mut builder = Builder::new();
+///
+/// builder.sink(Stdout);
+/// ```
+pub trait Sink: Sync + Send {
+    /// Writes a formatted message, without the terminating NUL byte.
+    ///
+    /// An error is counted as a dropped record, see [`Logger::stats`](crate::Logger::stats).
+    fn write(&self, record: &SinkRecord<'_>, msg: &[u8]) -> io::Result<()>;
+
+    /// Flushes messages the sink buffered, called by [`Log::flush`](log::Log::flush)
+    /// and [`flush_all`](crate::flush_all).
+    ///
+    /// The default implementation does nothing.
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The metadata of a message passed to a [`Sink`].
+#[derive(Copy, Clone, Debug)]
+pub struct SinkRecord<'a> {
+    pub(crate) log_type: LogType,
+    pub(crate) level: LogLevel,
+    pub(crate) domain: LogDomain,
+    pub(crate) tag: &'a CStr,
+    pub(crate) private: bool,
+}
+
+impl<'a> SinkRecord<'a> {
+    /// The hilog log type the message would have been printed with.
+    pub fn log_type(&self) -> LogType {
+        self.log_type
+    }
+
+    /// The hilog level of the message.
+    pub fn level(&self) -> LogLevel {
+        self.level
+    }
+
+    /// The domain of the message.
+    pub fn domain(&self) -> LogDomain {
+        self.domain
+    }
+
+    /// The tag of the message.
+    pub fn tag(&self) -> &'a CStr {
+        self.tag
+    }
+
+    /// Whether the message would have been printed as a private argument.
+    ///
+    /// This is the case for records with a `private::` target. The message is
+    /// passed in clear text, so the sink has to redact it if needed.
+    pub fn private(&self) -> bool {
+        self.private
+    }
+}
+
+/// The hook set with [`set_test_sink`].
+#[cfg(feature = "test-util")]
+pub type TestSinkFn = fn(LogLevel, LogDomain, &CStr, &[u8]);
+
+#[cfg(feature = "test-util")]
+static TEST_SINK: RwLock<Option<TestSinkFn>> = RwLock::new(None);
+
+/// Sends the messages of all loggers to `sink` instead of hilog, or stops doing so.
+///
+/// While a test sink is set, no hilog function is called at all: records are
+/// treated as loggable and every formatted message is passed to the hook, which
+/// takes precedence over a [`Sink`]. Private messages are passed as `<private>`,
+/// like hilog prints them outside of debug mode. This allows to test formats, also on the
+/// development host, where messages would otherwise be printed to stderr.
+///
+/// # Examples
+///
+/// ```
+/// use std::ffi::CStr;
+/// use hilog::{LogDomain, LogLevel};
+///
+/// fn print(_level: LogLevel, _domain: LogDomain, tag: &CStr, msg: &[u8]) {
+///     println!("{}: {}", tag.to_string_lossy(), String::from_utf8_lossy(msg));
+/// }
+///
+/// hilog::set_test_sink(Some(print));
+/// ```
+#[cfg(feature = "test-util")]
+pub fn set_test_sink(sink: Option<TestSinkFn>) {
+    *TEST_SINK.write().unwrap_or_else(|e| e.into_inner()) = sink;
+}
+
+/// Returns the hook set with [`set_test_sink`], if any.
+#[cfg(feature = "test-util")]
+pub(crate) fn test_sink() -> Option<TestSinkFn> {
+    *TEST_SINK.read().unwrap_or_else(|e| e.into_inner())
+}
@@ -0,0 +1,82 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use log::Level;
+
+/// Counters of a `Logger`, updated while logging.
+#[derive(Default)]
+pub(crate) struct Counters {
+    records_emitted: AtomicU64,
+    records_filtered: AtomicU64,
+    // Indexed by `Level as usize - 1`.
+    filtered_by_level: [AtomicU64; 5],
+    records_dropped: AtomicU64,
+    bytes_written: AtomicU64,
+}
+
+impl Counters {
+    /// Counts a record once, however many lines it was printed as.
+    pub(crate) fn emitted(&self) {
+        self.records_emitted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts the bytes of every line passed to hilog.
+    pub(crate) fn written(&self, bytes: usize) {
+        self.bytes_written.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a record as emitted or dropped, depending on how printing it went.
+    pub(crate) fn record<T, E>(&self, res: Result<T, E>) {
+        match res {
+            Ok(_) => self.emitted(),
+            Err(_) => self.dropped(),
+        }
+    }
+
+    pub(crate) fn filtered(&self, level: Level) {
+        self.records_filtered.fetch_add(1, Ordering::Relaxed);
+        self.filtered_by_level[level as usize - 1].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn filtered_by_level(&self) -> [u64; 5] {
+        self.filtered_by_level.each_ref().map(|count| count.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn dropped(&self) {
+        self.records_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> LoggerStats {
+        LoggerStats {
+            records_emitted: self.records_emitted.load(Ordering::Relaxed),
+            records_filtered: self.records_filtered.load(Ordering::Relaxed),
+            records_dropped: self.records_dropped.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A snapshot of the counters of a [`Logger`](crate::Logger).
+///
+/// Obtained with [`Logger::stats`](crate::Logger::stats). The counters are
+/// updated independently, so a snapshot taken while other threads are logging
+/// may be slightly inconsistent.
+#[non_exhaustive]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LoggerStats {
+    /// Records that were passed to hilog successfully.
+    ///
+    /// A record counts once, also if it is mirrored to other domains or printed
+    /// as two lines with [`Builder::dual_format`](crate::Builder::dual_format).
+    /// Messages of the logger itself, like the startup banner or heartbeats, are
+    /// not counted.
+    pub records_emitted: u64,
+    /// Records that were dropped by the filters or the hilog loggability check.
+    pub records_filtered: u64,
+    /// Records that were dropped because of an error, e.g. a failing format
+    /// function, a rejected write or a re-entrant log call.
+    pub records_dropped: u64,
+    /// Bytes of all lines passed to hilog, without the terminating NUL.
+    ///
+    /// Unlike [`LoggerStats::records_emitted`], this includes mirrored copies
+    /// and the messages of the logger itself.
+    pub bytes_written: u64,
+}
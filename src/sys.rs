@@ -0,0 +1,101 @@
+//! The hilog API, with stand-ins on targets other than OpenHarmony.
+//!
+//! `hilog-sys` links `libhilog_ndk.z.so`, which only exists on OpenHarmony. It is
+//! therefore only a dependency there, so that programs and tests using this crate
+//! also build on the development host, where the writer prints to stderr.
+
+use std::ffi::{c_uint, CStr};
+
+#[cfg(target_env = "ohos")]
+pub use hilog_sys::{LogLevel, LogType};
+#[cfg(not(target_env = "ohos"))]
+pub use host::{LogLevel, LogType};
+
+/// Whether hilog is available, i.e. the program was built for OpenHarmony.
+pub(crate) const HAS_HILOG: bool = cfg!(target_env = "ohos");
+
+/// Prints `msg` with `OH_LOG_Print`, returning its result.
+///
+/// Without hilog nothing is printed and `-1` is returned.
+pub(crate) fn print(log_type: LogType, level: LogLevel, domain: c_uint, tag: &CStr, format: &CStr, msg: &CStr) -> i32 {
+    #[cfg(target_env = "ohos")]
+    {
+        unsafe { hilog_sys::OH_LOG_Print(log_type, level, domain, tag.as_ptr(), format.as_ptr(), msg.as_ptr()) }
+    }
+    #[cfg(not(target_env = "ohos"))]
+    {
+        let _ = (log_type, level, domain, tag, format, msg);
+        -1
+    }
+}
+
+/// Whether hilog prints messages of `level` with `domain` and `tag`.
+///
+/// Without hilog everything is loggable, the filters of the logger still apply.
+pub(crate) fn is_loggable(domain: c_uint, tag: &CStr, level: LogLevel) -> bool {
+    #[cfg(target_env = "ohos")]
+    {
+        unsafe { hilog_sys::OH_LOG_IsLoggable(domain, tag.as_ptr(), level) }
+    }
+    #[cfg(not(target_env = "ohos"))]
+    {
+        let _ = (domain, tag, level);
+        true
+    }
+}
+
+/// Sets the lowest level hilog prints for the process. Does nothing without hilog.
+#[cfg(feature = "api-15")]
+pub(crate) fn set_min_log_level(level: LogLevel) {
+    #[cfg(target_env = "ohos")]
+    unsafe {
+        hilog_sys::OH_LOG_SetMinLogLevel(level)
+    };
+    #[cfg(not(target_env = "ohos"))]
+    let _ = level;
+}
+
+#[cfg(not(target_env = "ohos"))]
+mod host {
+    use std::ffi::c_uint;
+
+    /// Enumerates log types, like `hilog_sys::LogType`.
+    #[repr(transparent)]
+    #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+    pub struct LogType(pub c_uint);
+
+    impl LogType {
+        /// Third-party application logs
+        pub const LOG_APP: LogType = LogType(0);
+    }
+
+    /// Enumerates log levels, like `hilog_sys::LogLevel`.
+    #[repr(transparent)]
+    #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+    pub struct LogLevel(pub c_uint);
+
+    impl LogLevel {
+        /// Debug level to be used by `OH_LOG_DEBUG`
+        pub const LOG_DEBUG: LogLevel = LogLevel(3);
+        /// Informational level to be used by `OH_LOG_INFO`
+        pub const LOG_INFO: LogLevel = LogLevel(4);
+        /// Warning level to be used by `OH_LOG_WARN`
+        pub const LOG_WARN: LogLevel = LogLevel(5);
+        /// Error level to be used by `OH_LOG_ERROR`
+        pub const LOG_ERROR: LogLevel = LogLevel(6);
+        /// Fatal level to be used by `OH_LOG_FATAL`
+        pub const LOG_FATAL: LogLevel = LogLevel(7);
+    }
+
+    impl From<log::Level> for LogLevel {
+        fn from(level: log::Level) -> Self {
+            // Trace has no corresponding hilog level.
+            match level {
+                log::Level::Error => LogLevel::LOG_ERROR,
+                log::Level::Warn => LogLevel::LOG_WARN,
+                log::Level::Info => LogLevel::LOG_INFO,
+                log::Level::Debug | log::Level::Trace => LogLevel::LOG_DEBUG,
+            }
+        }
+    }
+}
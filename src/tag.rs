@@ -0,0 +1,106 @@
+use std::error::Error;
+use std::ffi::{CStr, CString};
+use std::fmt;
+
+/// A validated hilog tag.
+///
+/// A tag is at most [`Tag::MAX_LEN`] bytes long, not empty and contains no
+/// NUL bytes.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Tag(CString);
+
+impl Tag {
+    /// Maximum length of a tag in bytes, as accepted by hilog.
+    pub const MAX_LEN: usize = 31;
+
+    /// Creates a new tag, checking the hilog constraints.
+    pub fn new(tag: &str) -> Result<Tag, TagError> {
+        if tag.is_empty() {
+            return Err(TagError::Empty);
+        }
+        if tag.len() > Self::MAX_LEN {
+            return Err(TagError::TooLong(tag.len()));
+        }
+        CString::new(tag)
+            .map(Tag)
+            .map_err(|e| TagError::Nul(e.nul_position()))
+    }
+
+    /// Returns the tag as a C string.
+    pub fn as_c_str(&self) -> &CStr {
+        &self.0
+    }
+
+    /// Returns the tag as a string slice.
+    pub fn as_str(&self) -> &str {
+        // Tags are created from a `&str`, so they are valid UTF-8.
+        self.0.to_str().unwrap_or_default()
+    }
+}
+
+impl TryFrom<&str> for Tag {
+    type Error = TagError;
+
+    fn try_from(tag: &str) -> Result<Self, Self::Error> {
+        Tag::new(tag)
+    }
+}
+
+impl TryFrom<String> for Tag {
+    type Error = TagError;
+
+    fn try_from(tag: String) -> Result<Self, Self::Error> {
+        Tag::new(&tag)
+    }
+}
+
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Whether `tag` is a valid [`Tag`], usable in constant expressions.
+pub(crate) const fn is_valid(tag: &str) -> bool {
+    let bytes = tag.as_bytes();
+    if bytes.is_empty() || bytes.len() > Tag::MAX_LEN {
+        return false;
+    }
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == 0 {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// The error returned when a string is not a valid [`Tag`].
+#[allow(clippy::exhaustive_enums)] // compatibility
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TagError {
+    /// The tag is empty.
+    Empty,
+    /// The tag is longer than [`Tag::MAX_LEN`]; contains the actual length.
+    TooLong(usize),
+    /// The tag contains a NUL byte at the given position.
+    Nul(usize),
+}
+
+impl fmt::Display for TagError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TagError::Empty => f.write_str("tag is empty"),
+            TagError::TooLong(len) => write!(
+                f,
+                "tag is {} bytes long, at most {} are allowed",
+                len,
+                Tag::MAX_LEN
+            ),
+            TagError::Nul(pos) => write!(f, "tag contains a NUL byte at position {}", pos),
+        }
+    }
+}
+
+impl Error for TagError {}